@@ -0,0 +1,144 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+    process::{Child, Command, Stdio},
+};
+
+/// What to do with accepted paths instead of writing them.
+#[derive(Clone)]
+pub enum Exec {
+    /// Run the command once per path.
+    Each(String),
+    /// Run the command once with every path.
+    Batch(String),
+}
+
+/// The canonical path of an item. Matches of a content search are
+/// `path:line:col:text`, so the longest prefix naming a file is used.
+pub fn resolve(item: &str) -> io::Result<PathBuf> {
+    let err = match fs::canonicalize(item) {
+        Ok(path) => return Ok(path),
+        Err(e) => e,
+    };
+    let mut prefixes = item.rmatch_indices(':').map(|(i, _)| &item[..i]);
+    prefixes
+        .find(|prefix| Path::new(prefix).is_file())
+        .map_or(Err(err), fs::canonicalize)
+}
+
+/// Works out the canonical paths of many items while only canonicalizing
+/// their roots, once each. Symbolic links below the roots are not resolved.
+pub struct Resolver {
+    /// Whether items are content matches, `path:line:col:text`.
+    content: bool,
+    roots: HashMap<String, Option<PathBuf>>,
+}
+
+impl Resolver {
+    pub fn new(content: bool) -> Self {
+        Self {
+            content,
+            roots: HashMap::new(),
+        }
+    }
+
+    /// The path of `item` found under `root`. Items that don't start with
+    /// their root, like the lines of a command, are relative to the current
+    /// directory.
+    pub fn path(&mut self, item: &str, root: &str) -> Option<PathBuf> {
+        let path = Path::new(match self.content {
+            true => match_path(item),
+            false => item,
+        });
+        let (root, rel) = match path.strip_prefix(root) {
+            Ok(rel) if !root.is_empty() => (root, rel),
+            _ => (".", path),
+        };
+        let root = self
+            .roots
+            .entry(root.to_string())
+            .or_insert_with(|| fs::canonicalize(root).ok())
+            .as_ref()?;
+        let mut path = root.clone();
+        for component in rel.components() {
+            match component {
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    path.pop();
+                }
+                component => path.push(component),
+            }
+        }
+        Some(path)
+    }
+}
+
+/// The path of a content match, `path:line:col:text` where the path may
+/// contain colons too.
+fn match_path(item: &str) -> &str {
+    let number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    for (i, _) in item.match_indices(':') {
+        let mut fields = item[i + 1..].splitn(3, ':');
+        if let (Some(line), Some(col), Some(_)) = (fields.next(), fields.next(), fields.next())
+            && number(line)
+            && number(col)
+        {
+            return &item[..i];
+        }
+    }
+    item
+}
+
+/// Starts `cmd` with `sh -c`, `{}` standing for the paths, which are added
+/// at the end if it doesn't appear. The paths are passed as arguments so they
+/// need no quoting, quotes written around `{}` as for `fd -x` are dropped.
+/// The command prints to stderr, stdout is for the client.
+pub fn spawn(cmd: &str, paths: &[PathBuf]) -> io::Result<Child> {
+    let script = match cmd.contains("{}") {
+        true => cmd
+            .replace("'{}'", "{}")
+            .replace(r#""{}""#, "{}")
+            .replace("{}", r#""$@""#),
+        false => format!(r#"{cmd} "$@""#),
+    };
+    Command::new("sh")
+        .arg("-c")
+        .arg(script)
+        .arg("sh")
+        .args(paths)
+        .stdin(Stdio::null())
+        .stdout(io::stderr())
+        .spawn()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_path_with_colons() {
+        assert_eq!(match_path("src/main.rs:12:5:fn main() {"), "src/main.rs");
+        assert_eq!(match_path("a:b/c.rs:1:2:x"), "a:b/c.rs");
+        assert_eq!(match_path("a:1/b.rs:3:4:y: 5:6:z"), "a:1/b.rs");
+        assert_eq!(match_path("notes:1:2:"), "notes");
+        assert_eq!(match_path("a.rs:1:x:2:3:t"), "a.rs:1:x");
+        assert_eq!(match_path("no/match.rs"), "no/match.rs");
+    }
+
+    #[test]
+    fn resolver_under_root() {
+        let cwd = fs::canonicalize(".").unwrap();
+        let mut resolver = Resolver::new(true);
+        let path = resolver.path("./src/../src/main.rs:1:1:x", ".");
+        assert_eq!(path, Some(cwd.join("src/main.rs")));
+        let path = resolver.path("src/main.rs:1:1:x", "git ls-files");
+        assert_eq!(path, Some(cwd.join("src/main.rs")));
+    }
+}
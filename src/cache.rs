@@ -0,0 +1,115 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::walk::WalkOptions;
+use std::{
+    cmp::Reverse,
+    env, fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+/// Cached walks kept, the least recently saved ones are removed.
+const MAX_FILES: usize = 32;
+
+/// Reads the items cached by the last complete walk with `opts`.
+pub fn load(opts: &WalkOptions) -> Vec<Arc<str>> {
+    let Some((file, root)) = file(opts) else {
+        return Vec::new();
+    };
+    let Ok(f) = fs::File::open(file) else {
+        return Vec::new();
+    };
+    let mut lines = BufReader::new(f).lines().map_while(Result::ok);
+    // the file name is a hash, the root guards against collisions
+    if lines.next().as_deref() != root.to_str() {
+        return Vec::new();
+    }
+    lines.map(Arc::from).collect()
+}
+
+/// Replaces the cached items for `opts`.
+pub fn save(opts: &WalkOptions, items: &[Arc<str>]) {
+    let Some((file, root)) = file(opts) else {
+        return;
+    };
+    if let Err(e) = write(&file, &root, items) {
+        eprintln!("gf: writing {}: {e}", file.display());
+    }
+    if let Some(dir) = file.parent() {
+        prune(dir);
+    }
+}
+
+/// Removes the cache files beyond the `MAX_FILES` most recently saved, every
+/// combination of walk options has its own.
+fn prune(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter(|entry| is_cache_name(&entry.file_name().to_string_lossy()))
+        .filter_map(|entry| Some((entry.metadata().ok()?.modified().ok()?, entry.path())))
+        .collect();
+    if files.len() <= MAX_FILES {
+        return;
+    }
+    files.sort_unstable_by_key(|(modified, _)| Reverse(*modified));
+    for (_, file) in &files[MAX_FILES..] {
+        let _ = fs::remove_file(file);
+    }
+}
+
+fn is_cache_name(name: &str) -> bool {
+    name.len() == 16 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn write(file: &Path, root: &Path, items: &[Arc<str>]) -> io::Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // written next to the cache and renamed, so a reader never sees half of it
+    let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+    let mut out = BufWriter::new(fs::File::create(&tmp)?);
+    writeln!(out, "{}", root.display())?;
+    for item in items.iter().filter(|item| !item.contains('\n')) {
+        writeln!(out, "{item}")?;
+    }
+    out.into_inner().map_err(io::IntoInnerError::into_error)?;
+    fs::rename(tmp, file)
+}
+
+/// The cache file for `opts` and the canonical root it was walked from.
+/// Content searches are not cached.
+fn file(opts: &WalkOptions) -> Option<(PathBuf, PathBuf)> {
+    if opts.content.is_some() {
+        return None;
+    }
+    let root = fs::canonicalize(&opts.path).ok()?;
+    let key = format!("{}\0{opts:?}", root.display());
+    let dir = xdg_dir("XDG_CACHE_HOME", ".cache")?;
+    Some((dir.join(format!("{:016x}", fnv1a(key.as_bytes()))), root))
+}
+
+/// FNV-1a, which unlike `DefaultHasher` gives the same hash with every Rust
+/// release so the cache outlives toolchain upgrades.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// `$<var>/goldfish`, or `~/<fallback>/goldfish` if the variable is not set.
+pub fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    let base = match env::var_os(var).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(env::var_os("HOME")?).join(fallback),
+    };
+    Some(base.join("goldfish"))
+}
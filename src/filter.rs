@@ -0,0 +1,259 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use std::{
+    ffi::CString,
+    fmt,
+    fs::Metadata,
+    io, mem,
+    os::unix::fs::MetadataExt,
+    ptr,
+    time::{Duration, SystemTime},
+};
+
+/// Limits the size of files, `+10M` is at least 10 megabytes, `-10M` at most
+/// and `10M` exactly.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Size {
+    Min(u64),
+    Max(u64),
+    Exactly(u64),
+}
+
+impl Size {
+    pub fn matches(self, metadata: &Metadata) -> bool {
+        let len = metadata.len();
+        match self {
+            Size::Min(min) => len >= min,
+            Size::Max(max) => len <= max,
+            Size::Exactly(size) => len == size,
+        }
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Size::Min(min) => write!(f, "+{min}"),
+            Size::Max(max) => write!(f, "-{max}"),
+            Size::Exactly(size) => write!(f, "{size}"),
+        }
+    }
+}
+
+pub fn parse_size(s: &str) -> Result<Size, String> {
+    let (limit, rest): (fn(u64) -> Size, _) = match s.as_bytes().first() {
+        Some(b'+') => (Size::Min, &s[1..]),
+        Some(b'-') => (Size::Max, &s[1..]),
+        _ => (Size::Exactly, s),
+    };
+    Ok(limit(parse_bytes(rest)?))
+}
+
+/// Parses a number of bytes like `100`, `10k`, `10M` or `1Gi`.
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let (num, unit) = number(s).ok_or_else(|| format!("{s}: expected a number"))?;
+    let unit: u64 = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" => 1000,
+        "m" => 1000_u64.pow(2),
+        "g" => 1000_u64.pow(3),
+        "t" => 1000_u64.pow(4),
+        "ki" => 1 << 10,
+        "mi" => 1 << 20,
+        "gi" => 1 << 30,
+        "ti" => 1 << 40,
+        _ => return Err(format!("{s}: unknown unit {unit}")),
+    };
+    Ok(num.saturating_mul(unit))
+}
+
+/// Parses durations like `90s`, `10min`, `2h`, `3d`, `1w` or `1y`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (num, unit) = number(s).ok_or_else(|| format!("{s}: expected a number"))?;
+    let secs = match unit {
+        "s" | "sec" => 1,
+        "m" | "min" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(format!("{s}: expected a unit like s, min, h, d, w or y")),
+    };
+    Ok(Duration::from_secs(num.saturating_mul(secs)))
+}
+
+/// Splits the leading number off `s`.
+fn number(s: &str) -> Option<(u64, &str)> {
+    let (num, rest) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    Some((num.parse().ok()?, rest))
+}
+
+/// How long before `now` the entry was last modified.
+pub fn age(metadata: &Metadata, now: SystemTime) -> Option<Duration> {
+    now.duration_since(metadata.modified().ok()?).ok()
+}
+
+/// The user and group an entry must belong to, either can be left out.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    pub fn matches(self, metadata: &Metadata) -> bool {
+        self.uid.is_none_or(|uid| metadata.uid() == uid)
+            && self.gid.is_none_or(|gid| metadata.gid() == gid)
+    }
+}
+
+/// Shown as `uid:gid`, with the missing one left empty.
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(uid) = self.uid {
+            write!(f, "{uid}")?;
+        }
+        f.write_str(":")?;
+        if let Some(gid) = self.gid {
+            write!(f, "{gid}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `user`, `user:group` or `:group`, by name or id.
+pub fn parse_owner(s: &str) -> Result<Owner, String> {
+    let (user, group) = s.split_once(':').unwrap_or((s, ""));
+    let owner = Owner {
+        uid: match user {
+            "" => None,
+            _ => Some(user.parse().or_else(|_| uid(user))?),
+        },
+        gid: match group {
+            "" => None,
+            _ => Some(group.parse().or_else(|_| gid(group))?),
+        },
+    };
+    if owner.uid.is_none() && owner.gid.is_none() {
+        return Err("expected user, user:group or :group".into());
+    }
+    Ok(owner)
+}
+
+fn uid(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: an all zero passwd is valid, it is only read once filled in
+    let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+    let found = lookup(|buf, result| {
+        let mut entry = ptr::null_mut();
+        // SAFETY: the buffer and its length match, the name is nul terminated
+        let err = unsafe {
+            libc::getpwnam_r(
+                c_name.as_ptr(),
+                &mut passwd,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut entry,
+            )
+        };
+        *result = !entry.is_null();
+        err
+    })?;
+    match found {
+        true => Ok(passwd.pw_uid),
+        false => Err(format!("unknown user {name}")),
+    }
+}
+
+fn gid(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|e| e.to_string())?;
+    // SAFETY: an all zero group is valid, it is only read once filled in
+    let mut group: libc::group = unsafe { mem::zeroed() };
+    let found = lookup(|buf, result| {
+        let mut entry = ptr::null_mut();
+        // SAFETY: the buffer and its length match, the name is nul terminated
+        let err = unsafe {
+            libc::getgrnam_r(
+                c_name.as_ptr(),
+                &mut group,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut entry,
+            )
+        };
+        *result = !entry.is_null();
+        err
+    })?;
+    match found {
+        true => Ok(group.gr_gid),
+        false => Err(format!("unknown group {name}")),
+    }
+}
+
+/// Calls a `get*nam_r` function with a growing buffer until it fits, returns
+/// whether an entry was found. Unlike `getpwnam` these are safe to call from
+/// several sessions at once.
+fn lookup(
+    mut call: impl FnMut(&mut [libc::c_char], &mut bool) -> libc::c_int,
+) -> Result<bool, String> {
+    let mut buf = vec![0; 1024];
+    loop {
+        let mut found = false;
+        match call(&mut buf, &mut found) {
+            0 => return Ok(found),
+            libc::ERANGE if buf.len() < 1 << 20 => buf.resize(buf.len() * 2, 0),
+            err => return Err(io::Error::from_raw_os_error(err).to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("+10k"), Ok(Size::Min(10_000)));
+        assert_eq!(parse_size("-1Mi"), Ok(Size::Max(1 << 20)));
+        assert_eq!(parse_size("512"), Ok(Size::Exactly(512)));
+        assert!(parse_size("+").is_err());
+        assert!(parse_size("-").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("k").is_err());
+    }
+
+    #[test]
+    fn bytes_overflow() {
+        assert_eq!(
+            parse_bytes("99999999999999999999"),
+            Err("99999999999999999999: expected a number".into())
+        );
+        assert_eq!(parse_bytes("18446744073709551615T"), Ok(u64::MAX));
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("10min"), Ok(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Ok(Duration::from_secs(7200)));
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("10X").is_err());
+        assert!(parse_duration("min").is_err());
+    }
+
+    #[test]
+    fn owner() {
+        let owner = |uid, gid| Ok(Owner { uid, gid });
+        assert_eq!(parse_owner("0"), owner(Some(0), None));
+        assert_eq!(parse_owner("0:0"), owner(Some(0), Some(0)));
+        assert_eq!(parse_owner(":0"), owner(None, Some(0)));
+        assert_eq!(parse_owner("root:root"), owner(Some(0), Some(0)));
+        assert!(parse_owner(":").is_err());
+        assert!(parse_owner("").is_err());
+        assert!(parse_owner("no-such-user-gf").is_err());
+    }
+}
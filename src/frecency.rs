@@ -0,0 +1,134 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::cache;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Once the ranks add up to more than this, they are all aged so old entries
+/// fade out, like zoxide does.
+const MAX_RANK: f64 = 1000.0;
+
+/// Score added per point of frecency, about one matched character.
+const WEIGHT: f64 = 16.0;
+
+/// How often and how recently paths were accepted, saved in
+/// `$XDG_DATA_HOME/goldfish/frecency`.
+pub struct Frecency {
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    /// Grows by one every time the path is accepted.
+    rank: f64,
+    /// Seconds since the epoch of the last time.
+    last: u64,
+}
+
+impl Frecency {
+    pub fn load() -> Self {
+        Self {
+            entries: Mutex::new(read().unwrap_or_default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.lock().unwrap().is_empty()
+    }
+
+    /// Counts `paths` as accepted now and saves the store.
+    pub fn add(&self, paths: &[PathBuf]) {
+        let mut entries = self.entries.lock().unwrap();
+        // other processes may have saved since, start from their accepts
+        if let Some(saved) = read() {
+            *entries = saved;
+        }
+        let now = now();
+        for path in paths {
+            let entry = entries
+                .entry(path.clone())
+                .or_insert(Entry { rank: 0.0, last: 0 });
+            entry.rank += 1.0;
+            entry.last = now;
+        }
+        if entries.values().map(|e| e.rank).sum::<f64>() > MAX_RANK {
+            entries.retain(|_, e| {
+                e.rank *= 0.9;
+                e.rank >= 1.0
+            });
+        }
+        let Some(file) = file() else {
+            return;
+        };
+        if let Err(e) = write(&file, &entries) {
+            eprintln!("gf: writing {}: {e}", file.display());
+        }
+    }
+
+    /// Score to add to the match score of `path`, higher the more often and
+    /// recently it was accepted.
+    pub fn boost(&self, path: &Path) -> u32 {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(path) else {
+            return 0;
+        };
+        let age = now().saturating_sub(entry.last);
+        let recency = match age {
+            0..3600 => 4.0,
+            3600..86400 => 2.0,
+            86400..604800 => 0.5,
+            _ => 0.25,
+        };
+        (entry.rank * recency * WEIGHT) as u32
+    }
+}
+
+fn read() -> Option<HashMap<PathBuf, Entry>> {
+    let f = fs::File::open(file()?).ok()?;
+    let lines = BufReader::new(f).lines().map_while(Result::ok);
+    Some(lines.filter_map(|line| parse(&line)).collect())
+}
+
+/// Parses a `rank\tlast\tpath` line.
+fn parse(line: &str) -> Option<(PathBuf, Entry)> {
+    let mut fields = line.splitn(3, '\t');
+    let rank = fields.next()?.parse().ok()?;
+    let last = fields.next()?.parse().ok()?;
+    Some((PathBuf::from(fields.next()?), Entry { rank, last }))
+}
+
+fn write(file: &Path, entries: &HashMap<PathBuf, Entry>) -> io::Result<()> {
+    if let Some(dir) = file.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // renamed into place like the cache, other sessions may be reading it
+    let tmp = file.with_extension(format!("tmp{}", std::process::id()));
+    let mut out = BufWriter::new(fs::File::create(&tmp)?);
+    for (path, entry) in entries {
+        let Some(path) = path.to_str().filter(|p| !p.contains('\n')) else {
+            continue;
+        };
+        writeln!(out, "{}\t{}\t{path}", entry.rank, entry.last)?;
+    }
+    out.into_inner().map_err(io::IntoInnerError::into_error)?;
+    fs::rename(tmp, file)
+}
+
+fn file() -> Option<PathBuf> {
+    Some(cache::xdg_dir("XDG_DATA_HOME", ".local/share")?.join("frecency"))
+}
+
+fn now() -> u64 {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH);
+    since.map_or(0, |d| d.as_secs())
+}
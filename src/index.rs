@@ -0,0 +1,356 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::cache;
+use crate::source::Source;
+use crate::walk::{Sink, WalkError, WalkOptions};
+use crate::watch::Watch;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+    path::MAIN_SEPARATOR,
+    process::ExitStatus,
+    sync::{
+        Arc, Mutex, Weak,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Items found by one walk (or read from another source), shared by every
+/// session walking with the same options.
+pub struct Index {
+    inner: Mutex<Inner>,
+    cancel: AtomicBool,
+    /// Directories entered by the walk.
+    dirs: AtomicU64,
+    started: Instant,
+    /// Keep walking even when nobody is subscribed.
+    keep: bool,
+}
+
+struct Inner {
+    items: Vec<Arc<str>>,
+    /// Same as `items`, to skip paths reported again by the watcher.
+    known: HashSet<Arc<str>>,
+    /// Items loaded from the cache that the walk has not found yet.
+    stale: HashSet<Arc<str>>,
+    subscribers: Vec<Subscriber>,
+    next_id: u64,
+    done: bool,
+    /// How long the walk took, once done.
+    elapsed: Option<Duration>,
+    /// Exit status of a command source.
+    exit: Option<ExitStatus>,
+    errors: Vec<WalkError>,
+}
+
+/// Sent to subscribers when the items change in ways other than new items.
+pub enum Update {
+    /// The walk is complete.
+    Done,
+    /// The command producing the items exited, sent before `Done`.
+    Exited(ExitStatus),
+    /// Items were removed, the subscriber has to start over.
+    Reset,
+}
+
+type Callbacks = (Box<dyn Fn(Arc<str>) + Send>, Box<dyn Fn(Update) + Send>);
+
+struct Subscriber {
+    id: u64,
+    push: Box<dyn Fn(Arc<str>) + Send>,
+    update: Box<dyn Fn(Update) + Send>,
+}
+
+/// Keeps receiving items from an [`Index`] until dropped.
+pub struct Subscription {
+    index: Arc<Index>,
+    id: u64,
+}
+
+impl Index {
+    fn spawn(source: Source, keep: bool, watch: bool, cache: bool) -> Arc<Self> {
+        let index = Arc::new(Index {
+            inner: Mutex::new(Inner {
+                items: Vec::new(),
+                known: HashSet::new(),
+                stale: HashSet::new(),
+                subscribers: Vec::new(),
+                next_id: 0,
+                done: false,
+                elapsed: None,
+                exit: None,
+                errors: Vec::new(),
+            }),
+            cancel: AtomicBool::new(false),
+            dirs: AtomicU64::new(0),
+            started: Instant::now(),
+            keep,
+        });
+
+        // only walks are cached and watched
+        let walk = match &source {
+            Source::Walk(opts) => Some(WalkOptions::clone(opts)),
+            _ => None,
+        };
+        let cached = walk.clone().filter(|_| cache);
+        let watched = walk.filter(|_| watch);
+        // loaded right away, so the first query is answered from the cache
+        if let Some(opts) = &cached {
+            index.restore(cache::load(opts));
+        }
+
+        let walker = index.clone();
+        thread::spawn(move || {
+            // started first so nothing changed during the walk is missed
+            let watch = watched.as_ref().and_then(Watch::new);
+            let exit = source.read(&walker.cancel, &*walker);
+            if walker.canceled() {
+                return;
+            }
+            let items = walker.finish(exit);
+            if let Some(opts) = &cached {
+                cache::save(opts, &items);
+            }
+            if let (Some(watch), Some(opts)) = (watch, watched) {
+                let index = Arc::downgrade(&walker);
+                drop(walker);
+                watch.run(index, &opts);
+            }
+        });
+        index
+    }
+
+    /// Adds items from the cache, those the walk does not find again are
+    /// removed once it finishes.
+    fn restore(&self, items: Vec<Arc<str>>) {
+        let mut inner = self.inner.lock().unwrap();
+        for item in items {
+            if !inner.known.insert(item.clone()) {
+                continue;
+            }
+            for sub in &inner.subscribers {
+                (sub.push)(item.clone());
+            }
+            inner.stale.insert(item.clone());
+            inner.items.push(item);
+        }
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        self.inner.lock().unwrap().known.contains(item)
+    }
+
+    /// Removes `paths` and everything below them, subscribers are told to
+    /// start over if anything was removed.
+    pub fn remove(&self, paths: &[String]) {
+        let paths: HashSet<&str> = paths.iter().map(String::as_str).collect();
+        // an item goes if it or one of its parents was removed
+        self.inner.lock().unwrap().retain(|item| {
+            let mut parents = item.match_indices(MAIN_SEPARATOR).map(|(i, _)| &item[..i]);
+            !paths.contains(item) && !parents.any(|parent| paths.contains(parent))
+        });
+    }
+
+    /// Marks the walk complete, dropping cached items it did not find.
+    /// Returns the items found.
+    fn finish(&self, exit: Option<ExitStatus>) -> Vec<Arc<str>> {
+        let mut inner = self.inner.lock().unwrap();
+        let stale = mem::take(&mut inner.stale);
+        if !stale.is_empty() {
+            inner.retain(|item| !stale.contains(item));
+        }
+        inner.done = true;
+        inner.elapsed = Some(self.started.elapsed());
+        inner.exit = exit;
+        for sub in &inner.subscribers {
+            if let Some(status) = exit {
+                (sub.update)(Update::Exited(status));
+            }
+            (sub.update)(Update::Done);
+        }
+        inner.items.clone()
+    }
+
+    /// Calls `push` with every item found so far, followed by all items
+    /// found later. `update` is told once the walk is complete, right away if
+    /// it already is. Hands the callbacks back if the walk was canceled
+    /// because its last subscriber left.
+    fn subscribe(
+        self: &Arc<Self>,
+        push: Box<dyn Fn(Arc<str>) + Send>,
+        update: Box<dyn Fn(Update) + Send>,
+    ) -> Result<Subscription, Callbacks> {
+        let mut inner = self.inner.lock().unwrap();
+        // checked under the lock the last subscriber cancels with
+        if self.canceled() {
+            return Err((push, update));
+        }
+        for item in &inner.items {
+            push(item.clone());
+        }
+        if let Some(status) = inner.exit {
+            update(Update::Exited(status));
+        }
+        if inner.done {
+            update(Update::Done);
+        }
+
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.subscribers.push(Subscriber { id, push, update });
+        Ok(Subscription {
+            index: self.clone(),
+            id,
+        })
+    }
+
+    pub fn canceled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+impl Sink for Index {
+    fn push(&self, item: Arc<str>) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.stale.is_empty() {
+            inner.stale.remove(&item);
+        }
+        if !inner.known.insert(item.clone()) {
+            return;
+        }
+        for sub in &inner.subscribers {
+            (sub.push)(item.clone());
+        }
+        inner.items.push(item);
+    }
+
+    fn error(&self, error: WalkError) {
+        self.inner.lock().unwrap().errors.push(error);
+    }
+
+    fn dir(&self) {
+        self.dirs.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Inner {
+    /// Keeps the items `keep` returns true for, subscribers are told to start
+    /// over if anything was removed.
+    fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        let count = self.items.len();
+        self.items.retain(|item| keep(item));
+        if self.items.len() == count {
+            return;
+        }
+        self.known.retain(|item| keep(item));
+        self.stale.retain(|item| keep(item));
+        for sub in &self.subscribers {
+            (sub.update)(Update::Reset);
+        }
+    }
+}
+
+/// How far a walk got.
+pub struct Stats {
+    pub items: usize,
+    pub dirs: u64,
+    pub errors: usize,
+    /// Time spent walking, so far or in total.
+    pub elapsed: Duration,
+    pub done: bool,
+}
+
+impl Subscription {
+    /// Entries the walk could not read so far.
+    pub fn errors(&self) -> Vec<WalkError> {
+        self.index.inner.lock().unwrap().errors.clone()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.index.inner.lock().unwrap();
+        Stats {
+            items: inner.items.len(),
+            dirs: self.index.dirs.load(Ordering::Relaxed),
+            errors: inner.errors.len(),
+            elapsed: inner
+                .elapsed
+                .unwrap_or_else(|| self.index.started.elapsed()),
+            done: inner.done,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    /// Stops the walk if nobody else is waiting for it.
+    fn drop(&mut self) {
+        let mut inner = self.index.inner.lock().unwrap();
+        inner.subscribers.retain(|sub| sub.id != self.id);
+        if inner.subscribers.is_empty() && !self.index.keep {
+            self.index.cancel.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Hands out the [`Index`] for a source, starting a walk only if no session
+/// already has one.
+pub struct Registry {
+    indexes: Mutex<HashMap<Source, Weak<Index>>>,
+    /// Keep indexes up to date with changes on disk.
+    watch: bool,
+    /// Start from the items of the last walk and save new walks.
+    cache: bool,
+    /// The indexes for the startup sources, kept between clients.
+    _pinned: Vec<Arc<Index>>,
+}
+
+impl Registry {
+    pub fn new(sources: Vec<Source>, watch: bool, cache: bool) -> Arc<Self> {
+        let mut indexes = HashMap::new();
+        let pinned = sources
+            .into_iter()
+            .map(|source| {
+                let index = Index::spawn(source.clone(), true, watch, cache);
+                indexes.insert(source, Arc::downgrade(&index));
+                index
+            })
+            .collect();
+        Arc::new(Registry {
+            indexes: Mutex::new(indexes),
+            watch,
+            cache,
+            _pinned: pinned,
+        })
+    }
+
+    /// Subscribes to the index for `source`, see [`Index::subscribe`].
+    pub fn subscribe(
+        &self,
+        source: &Source,
+        push: impl Fn(Arc<str>) + Send + 'static,
+        update: impl Fn(Update) + Send + 'static,
+    ) -> Subscription {
+        let mut indexes = self.indexes.lock().unwrap();
+        let mut callbacks: Callbacks = (Box::new(push), Box::new(update));
+        if let Some(index) = indexes.get(source).and_then(Weak::upgrade) {
+            match index.subscribe(callbacks.0, callbacks.1) {
+                Ok(sub) => return sub,
+                // the last subscriber left right before, walk again
+                Err(back) => callbacks = back,
+            }
+        }
+        indexes.retain(|_, index| index.strong_count() > 0);
+        let index = Index::spawn(source.clone(), false, self.watch, self.cache);
+        indexes.insert(source.clone(), Arc::downgrade(&index));
+        let (push, update) = callbacks;
+        match index.subscribe(push, update) {
+            Ok(sub) => sub,
+            Err(_) => unreachable!("only the last subscriber cancels"),
+        }
+    }
+}
@@ -4,15 +4,43 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use clap::Parser;
-use ignore::WalkState;
-use nucleo::{
-    Nucleo,
-    pattern::{CaseMatching, Normalization},
-};
+use accept::Exec;
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use filter::{Owner, Size};
+use frecency::Frecency;
+use index::Registry;
+use nucleo::pattern::{CaseMatching, Normalization};
+use output::Format;
+use session::{Options, Session};
+use source::Source;
 use std::{
-    io::{self, BufRead, Write}, sync::Arc, thread, time::Instant
+    fs,
+    io::{self, BufReader},
+    net::Shutdown,
+    os::{
+        fd::RawFd,
+        unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
 };
+use walk::{Kind, WalkOptions};
+
+mod accept;
+mod cache;
+mod filter;
+mod frecency;
+mod index;
+mod output;
+mod session;
+mod source;
+mod walk;
+mod watch;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -21,17 +49,26 @@ struct Cli {
     #[arg(short = 'q', long = "query")]
     pattern: Option<String>,
 
-    /// Path to search. Defaults to current directory.
-    path: Option<String>,
+    /// Paths to search, walked in parallel. Defaults to current directory.
+    #[arg(value_name = "PATH")]
+    paths: Vec<String>,
 
-    /// Make searching case-insensitive default (smart-case)
-    #[arg(short, long, default_value_t = false)]
+    /// Make searching case-insensitive
+    #[arg(short, long, overrides_with_all = ["smart_case", "case_sensitive"])]
     ignore_case: bool,
 
-    /// Make searching case-insensitive
-    #[arg(short, long, default_value_t = true)]
+    /// Make searching case-insensitive unless the query has uppercase characters (default)
+    #[arg(short, long, overrides_with_all = ["ignore_case", "case_sensitive"])]
     smart_case: bool,
 
+    /// Make searching case-sensitive
+    #[arg(short = 'C', long, overrides_with_all = ["ignore_case", "smart_case"])]
+    case_sensitive: bool,
+
+    /// Match accented characters exactly instead of by their base letter
+    #[arg(long)]
+    no_normalize: bool,
+
     /// disable all default ignored files (.gitignore, target, node_modules)
     #[arg(short = 'A', long, default_value_t = false)]
     no_ignore: bool,
@@ -43,93 +80,268 @@ struct Cli {
     /// follow symbolic links
     #[arg(short = 'L', long = "follow", default_value_t = false)]
     follow_symlinks: bool,
+
+    /// Only list files matching this glob, can be repeated
+    #[arg(long, value_name = "GLOB", value_parser = walk::parse_glob)]
+    glob: Vec<String>,
+
+    /// Skip files and directories matching this glob, can be repeated
+    #[arg(short = 'E', long, value_name = "GLOB", value_parser = walk::parse_glob)]
+    exclude: Vec<String>,
+
+    /// Only list files of this type (see `c:types`), can be repeated
+    #[arg(short, long = "type", value_name = "TYPE")]
+    r#type: Vec<String>,
+
+    /// Skip files of this type, can be repeated
+    #[arg(short = 'T', long, value_name = "TYPE")]
+    type_not: Vec<String>,
+
+    /// Define a file type like `foo:*.foo`, can be repeated
+    #[arg(long, value_name = "TYPE:GLOB", value_parser = walk::parse_type_def)]
+    type_add: Vec<String>,
+
+    /// Only list entries of this kind, can be repeated
+    #[arg(short, long, value_enum, value_name = "KIND")]
+    kind: Vec<Kind>,
+
+    /// Only list files of this size, `+10M` for at least 10MB, `-10k` for at most 10kB, can be repeated
+    #[arg(short = 'S', long, value_name = "SIZE", allow_hyphen_values = true, value_parser = filter::parse_size)]
+    size: Vec<Size>,
+
+    /// Only list entries modified within this duration, like `2d` or `10min`
+    #[arg(long, value_name = "DURATION", value_parser = filter::parse_duration)]
+    changed_within: Option<Duration>,
+
+    /// Only list entries modified longer ago than this duration
+    #[arg(long, value_name = "DURATION", value_parser = filter::parse_duration)]
+    changed_before: Option<Duration>,
+
+    /// Only list entries owned by this user and/or group, as `user`, `user:group` or `:group`
+    #[arg(short, long, value_name = "USER:GROUP", value_parser = filter::parse_owner)]
+    owner: Option<Owner>,
+
+    /// Don't descend more than this many directories below the roots
+    #[arg(short = 'd', long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Only list entries at least this many directories below the roots
+    #[arg(long, value_name = "DEPTH")]
+    min_depth: Option<usize>,
+
+    /// Don't cross into other file systems, like network mounts or /proc
+    #[arg(long, default_value_t = false)]
+    one_file_system: bool,
+
+    /// Skip files larger than this, like `10M`
+    #[arg(long, value_name = "SIZE", value_parser = filter::parse_bytes)]
+    max_filesize: Option<u64>,
+
+    /// Search file contents for a regex, listing `path:line:col:text` matches
+    #[arg(short = 'g', long, value_name = "REGEX")]
+    content: Option<String>,
+
+    /// Output format of the results
+    #[arg(short, long, value_enum, default_value_t = Format::Plain)]
+    format: Format,
+
+    /// Number of results per response
+    #[arg(short = 'n', long, default_value_t = 10)]
+    limit: u32,
+
+    /// Write updated results while items are added, and `done` once the walk finishes
+    #[arg(long, default_value_t = false)]
+    stream: bool,
+
+    /// Write the progress of the walk every half second, and once it finishes
+    #[arg(long, default_value_t = false)]
+    progress: bool,
+
+    /// Keep the results up to date with files created and deleted after the walk
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Start from the files found by the last walk, cached in $XDG_CACHE_HOME/goldfish,
+    /// while a new walk catches up
+    #[arg(long, default_value_t = false)]
+    cache: bool,
+
+    /// Run a command with `sh -c` for every accepted path (`s:`) instead of writing it,
+    /// `{}` is replaced by the path
+    #[arg(long, value_name = "CMD", conflicts_with = "exec_batch")]
+    exec: Option<String>,
+
+    /// Run a command once with all the accepted paths in place of `{}`
+    #[arg(long, value_name = "CMD")]
+    exec_batch: Option<String>,
+
+    /// Rank the paths accepted often and recently higher, remembered in
+    /// $XDG_DATA_HOME/goldfish/frecency
+    #[arg(long, default_value_t = false)]
+    frecency: bool,
+
+    /// Match the lines of a file instead of walking
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["paths", "items_fd"])]
+    items: Option<PathBuf>,
+
+    /// Match the lines read from an inherited file descriptor instead of walking
+    #[arg(long, value_name = "FD", conflicts_with = "paths", value_parser = parse_fd)]
+    items_fd: Option<RawFd>,
+
+    /// Match the lines printed by a shell command instead of walking
+    #[arg(long, value_name = "CMD", conflicts_with_all = ["paths", "items", "items_fd"])]
+    source_cmd: Option<String>,
+
+    /// Serve clients on a unix socket instead of stdin, sharing walks between them
+    #[arg(long, value_name = "SOCKET")]
+    listen: Option<PathBuf>,
 }
 
 fn main() -> Result<(), io::Error> {
     let cli = Cli::parse();
-    let path = cli.path.unwrap_or(".".to_string());
-
-    let mut m: Nucleo<String> = Nucleo::new(
-        nucleo::Config::DEFAULT.match_paths(),
-        Arc::new(|| {}),
-        None,
-        1,
-    );
-    let inj = Arc::new(m.injector());
-
-    thread::spawn(move || {
-        ignore::WalkBuilder::new(path)
-            .require_git(false)
-            .follow_links(cli.follow_symlinks)
-            .standard_filters(!cli.no_ignore)
-            .hidden(!cli.hidden)
-            .threads(thread::available_parallelism().unwrap().get())
-            .build_parallel()
-            .run(|| {
-                let inj = inj.clone();
-                Box::new(move |entry| {
-                    let entry = match entry {
-                        Ok(e) => e.into_path(),
-                        Err(_) => return WalkState::Continue,
-                    };
-                    // println!("{}", &entry.to_str().unwrap());
-                    inj.push(entry.to_string_lossy().into(), |e, cols| {
-                        cols[0] = e.to_owned().into()
-                    });
-                    WalkState::Continue
-                })
-            });
-    });
-
-    interactive(&mut m)?;
+    let roots = match cli.paths.is_empty() {
+        true => vec![".".to_string()],
+        false => cli.paths,
+    };
+    // the path is set per root
+    let walk = WalkOptions {
+        path: String::new(),
+        subtree_of: None,
+        no_ignore: cli.no_ignore,
+        hidden: cli.hidden,
+        follow_symlinks: cli.follow_symlinks,
+        content: cli.content,
+        globs: cli.glob,
+        excludes: cli.exclude,
+        types: cli.r#type,
+        types_not: cli.type_not,
+        type_defs: cli.type_add,
+        kinds: cli.kind,
+        sizes: cli.size,
+        changed_within: cli.changed_within,
+        changed_before: cli.changed_before,
+        owner: cli.owner,
+        max_depth: cli.max_depth,
+        min_depth: cli.min_depth,
+        one_file_system: cli.one_file_system,
+        max_filesize: cli.max_filesize,
+    };
+    if let Err(e) = walk::file_types(&walk) {
+        Cli::command().error(ErrorKind::InvalidValue, e).exit();
+    }
+    let opts = Options {
+        case: match (cli.ignore_case, cli.case_sensitive) {
+            (true, _) => CaseMatching::Ignore,
+            (_, true) => CaseMatching::Respect,
+            _ => CaseMatching::Smart,
+        },
+        normalization: match cli.no_normalize {
+            true => Normalization::Never,
+            false => Normalization::Smart,
+        },
+        format: cli.format,
+        stream: cli.stream,
+        progress: cli.progress,
+        limit: cli.limit,
+        exec: match (cli.exec, cli.exec_batch) {
+            (Some(cmd), _) => Some(Exec::Each(cmd)),
+            (_, Some(cmd)) => Some(Exec::Batch(cmd)),
+            _ => None,
+        },
+        frecency: cli.frecency.then(|| Arc::new(Frecency::load())),
+    };
+
+    let source = match (cli.items, cli.items_fd, cli.source_cmd) {
+        (Some(file), _, _) => Some(Source::File(file)),
+        (_, Some(fd), _) => Some(Source::Fd(fd)),
+        (_, _, Some(cmd)) => Some(Source::Command(cmd)),
+        _ => None,
+    };
+    let sources = Source::all(source.as_ref(), &walk, &roots);
+    let registry = Registry::new(sources, cli.watch, cli.cache);
+    if let Some(socket) = cli.listen {
+        return listen(&socket, registry, walk, roots, source, opts);
+    }
+
+    let input = BufReader::new(io::stdin());
+    let mut session = Session::new(input, io::stdout(), registry, walk, roots, source, opts);
+    session.run()?;
     Ok(())
 }
 
-fn interactive(m: &mut Nucleo<String>) -> Result<(), io::Error> {
-    let stdin = io::stdin();
-    let mut stdout = io::stdout();
-    let reader = io::BufReader::new(stdin);
-    let mut last_query = String::new();
-
-    for line in reader.lines() {
-        let msg = line?;
-        if let Some(cmd) = msg.strip_prefix("c:") {
-            match cmd {
-                "Exit" => break,
-                _ => (),
+/// Parses `--items-fd`, which must be open and can't be stdin, stdout or
+/// stderr: the index takes ownership of it and closes it once read.
+fn parse_fd(s: &str) -> Result<RawFd, String> {
+    let fd: RawFd = s.parse().map_err(|e| format!("{s}: {e}"))?;
+    if (0..=2).contains(&fd) {
+        return Err(format!("{fd} is stdin, stdout or stderr"));
+    }
+    // SAFETY: only queries the descriptor flags
+    if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return Err(format!("{fd}: {}", io::Error::last_os_error()));
+    }
+    Ok(fd)
+}
+
+/// Runs a session for every connection to `socket`.
+fn listen(
+    socket: &Path,
+    registry: Arc<Registry>,
+    walk: WalkOptions,
+    roots: Vec<String>,
+    source: Option<Source>,
+    opts: Options,
+) -> Result<(), io::Error> {
+    // a socket left behind by a previous run would make bind fail, unless
+    // another gf still answers on it
+    if fs::symlink_metadata(socket).is_ok_and(|m| m.file_type().is_socket()) {
+        match UnixStream::connect(socket) {
+            Ok(_) => {
+                let msg = format!("{}: another gf is listening", socket.display());
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+            }
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(socket)?,
+            Err(e) => return Err(e),
+        }
+    }
+    let listener = UnixListener::bind(socket)?;
+    // sessions can run commands and read any file, only for this user
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("gf: {e}");
+                continue;
             }
-        } else if let Some(query) = msg.strip_prefix("q:") {
-            if query == last_query {
+        };
+        // one handle to read, one to write and one to close the connection
+        let (input, conn) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(input), Ok(conn)) => (BufReader::new(input), conn),
+            (Err(e), _) | (_, Err(e)) => {
+                eprintln!("gf: {e}");
                 continue;
             }
-
-            m.pattern.reparse(
-                0,
-                query,
-                CaseMatching::Smart,
-                Normalization::Smart,
-                query.starts_with(&last_query),
-            );
-            last_query = query.to_string();
-
-            let loop_time = Instant::now();
-            loop {
-                let s = m.tick(10);
-
-                if !s.running || loop_time.elapsed().as_millis() > 900 as u128 {
-                    if s.changed {
-                        let snapshot = m.snapshot();
-                        let count = 10.min(snapshot.matched_item_count());
-                        for result in snapshot.matched_items(..count) {
-                            stdout.write(result.data.as_bytes())?;
-                            stdout.write(b"\n")?;
-                        }
-                        stdout.flush()?;
-                    }
-                    break;
-                }
+        };
+        let mut session = Session::new(
+            input,
+            stream,
+            registry.clone(),
+            walk.clone(),
+            roots.clone(),
+            source.clone(),
+            opts.clone(),
+        );
+        thread::spawn(move || {
+            match session.run() {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => eprintln!("gf: {e}"),
+                _ => (),
             }
-        }
+            // the reader thread still holds the connection, the client
+            // would never see it close after c:Exit
+            let _ = conn.shutdown(Shutdown::Both);
+        });
     }
     Ok(())
 }
@@ -0,0 +1,362 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::index::Stats;
+use crate::walk::WalkError;
+use clap::ValueEnum;
+use ignore::types::FileTypeDef;
+use nucleo::{Item, Matcher, Snapshot};
+use serde_json::{Map, Value, json};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::ExitStatus,
+    sync::Arc,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One result per line, nothing is printed if the results did not change
+    Plain,
+    /// One JSON object per response
+    Jsonl,
+}
+
+/// An item in the matcher and the root it was found under.
+pub struct Entry {
+    pub item: Arc<str>,
+    pub root: Arc<str>,
+}
+
+/// A batch of results answering a query.
+pub struct Response<'a> {
+    /// Request id of the query, if the client sent one.
+    pub id: Option<&'a str>,
+    pub query: &'a str,
+    pub snapshot: &'a Snapshot<Entry>,
+    /// Index of the first match written.
+    pub offset: u32,
+    /// The matches to write, in order.
+    pub items: Vec<Item<'a, Entry>>,
+    /// Items to flag as marked.
+    pub marks: &'a [Arc<str>],
+    /// The snapshot differs from the last one written.
+    pub changed: bool,
+    /// Items are still being added.
+    pub running: bool,
+}
+
+impl Format {
+    pub fn write(
+        self,
+        out: &mut impl Write,
+        res: &Response,
+        matcher: &mut Matcher,
+    ) -> io::Result<()> {
+        match self {
+            Format::Plain => {
+                if let Some(id) = res.id {
+                    writeln!(out, "#{id}")?;
+                }
+                if !res.changed {
+                    return out.flush();
+                }
+                // streamed batches arrive unasked, the header tells where
+                // each starts and how many lines it has
+                let matched = res.snapshot.matched_item_count();
+                writeln!(out, "#results {} {matched}", res.items.len())?;
+                let mut marked = Vec::new();
+                for (i, result) in (res.offset..).zip(&res.items) {
+                    // an item starting with `#` would read as a control
+                    // line, it gets a `\` in front, and so do those
+                    // starting with `\` to tell them apart
+                    let item = &*result.data.item;
+                    if item.starts_with(['#', '\\']) {
+                        out.write_all(b"\\")?;
+                    }
+                    out.write_all(item.as_bytes())?;
+                    out.write_all(b"\n")?;
+                    if res.marks.contains(&result.data.item) {
+                        marked.push(i.to_string());
+                    }
+                }
+                if !marked.is_empty() {
+                    writeln!(out, "#marked {}", marked.join(" "))?;
+                }
+            }
+            Format::Jsonl => {
+                let mut msg = json!({
+                    "event": "results",
+                    "query": res.query,
+                    "matched": res.snapshot.matched_item_count(),
+                    "total": res.snapshot.item_count(),
+                    "running": res.running,
+                    "offset": res.offset,
+                    "marks": res.marks.len(),
+                    "results": results(res.snapshot, &res.items, res.marks, matcher),
+                });
+                if let Some(id) = res.id {
+                    msg["id"] = id.into();
+                }
+                writeln!(out, "{msg}")?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Announces that the walk finished with `total` items.
+    pub fn write_done(self, out: &mut impl Write, total: u32) -> io::Result<()> {
+        match self {
+            Format::Plain => writeln!(out, "#done")?,
+            Format::Jsonl => writeln!(out, "{}", json!({"event": "done", "total": total}))?,
+        }
+        out.flush()
+    }
+
+    /// Reports how far the walk got. `state` describes the session for
+    /// `c:status`, one `#key value` line per field in plain mode.
+    pub fn write_status(
+        self,
+        out: &mut impl Write,
+        stats: &Stats,
+        state: Option<&Map<String, Value>>,
+    ) -> io::Result<()> {
+        match self {
+            Format::Plain => {
+                writeln!(
+                    out,
+                    "#status items:{} dirs:{} errors:{} elapsed:{:.2}s running:{}",
+                    stats.items,
+                    stats.dirs,
+                    stats.errors,
+                    stats.elapsed.as_secs_f64(),
+                    !stats.done,
+                )?;
+                for (key, value) in state.into_iter().flatten() {
+                    match plain(value).as_str() {
+                        "" => writeln!(out, "#{key}")?,
+                        value => writeln!(out, "#{key} {value}")?,
+                    }
+                }
+            }
+            Format::Jsonl => {
+                let mut msg = json!({
+                    "event": "status",
+                    "items": stats.items,
+                    "dirs": stats.dirs,
+                    "errors": stats.errors,
+                    "elapsed_ms": stats.elapsed.as_millis() as u64,
+                    "running": !stats.done,
+                });
+                for (key, value) in state.into_iter().flatten() {
+                    msg[key] = value.clone();
+                }
+                writeln!(out, "{msg}")?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Reports a message that could not be handled.
+    pub fn write_error(self, out: &mut impl Write, message: &str) -> io::Result<()> {
+        match self {
+            Format::Plain => writeln!(out, "#error {}", one_line(message))?,
+            Format::Jsonl => writeln!(out, "{}", json!({"event": "error", "message": message}))?,
+        }
+        out.flush()
+    }
+
+    /// Lists the entries the walk could not read, with counts by kind.
+    pub fn write_errors(self, out: &mut impl Write, errors: &[WalkError]) -> io::Result<()> {
+        let mut counts = BTreeMap::new();
+        for error in errors {
+            *counts.entry(error.kind).or_insert(0) += 1;
+        }
+        match self {
+            Format::Plain => {
+                let counts: Vec<String> = counts
+                    .iter()
+                    .map(|(kind, count)| format!("{kind}:{count}"))
+                    .collect();
+                writeln!(out, "#errors {}", counts.join(" "))?;
+                // tab separated, paths and messages may have spaces, and a
+                // partial error has one line per glob that failed
+                for error in errors {
+                    let path = error.path.as_ref().map(|p| p.to_string_lossy());
+                    let path = path.unwrap_or_default().replace(['\n', '\r'], " ");
+                    let message = one_line(&error.message);
+                    writeln!(out, "#error {}\t{path}\t{message}", error.kind)?;
+                }
+            }
+            Format::Jsonl => {
+                let errors: Vec<Value> = errors
+                    .iter()
+                    .map(|error| {
+                        json!({
+                            "kind": error.kind,
+                            "path": error.path.as_ref().map(|p| p.to_string_lossy()),
+                            "message": error.message,
+                        })
+                    })
+                    .collect();
+                writeln!(
+                    out,
+                    "{}",
+                    json!({"event": "errors", "counts": counts, "errors": errors})
+                )?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Lists the file types usable with `c:type`.
+    pub fn write_types(self, out: &mut impl Write, types: &[FileTypeDef]) -> io::Result<()> {
+        match self {
+            Format::Plain => {
+                for def in types {
+                    writeln!(out, "#type {}: {}", def.name(), def.globs().join(", "))?;
+                }
+            }
+            Format::Jsonl => {
+                let types: Vec<Value> = types
+                    .iter()
+                    .map(|def| json!({"name": def.name(), "globs": def.globs()}))
+                    .collect();
+                writeln!(out, "{}", json!({"event": "types", "types": types}))?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Echoes the accepted paths.
+    pub fn write_accept(self, out: &mut impl Write, paths: &[PathBuf]) -> io::Result<()> {
+        match self {
+            Format::Plain => {
+                for path in paths {
+                    writeln!(out, "#accept {}", path.display())?;
+                }
+            }
+            Format::Jsonl => {
+                let paths: Vec<_> = paths.iter().map(|p| p.to_string_lossy()).collect();
+                writeln!(out, "{}", json!({"event": "accept", "paths": paths}))?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Reports how the `--exec` command run for accepted `paths` exited.
+    pub fn write_exec(
+        self,
+        out: &mut impl Write,
+        command: &str,
+        paths: &[PathBuf],
+        status: ExitStatus,
+    ) -> io::Result<()> {
+        match self {
+            Format::Plain => match (status.code(), status.signal()) {
+                (Some(code), _) => writeln!(out, "#exec {code}")?,
+                (_, Some(signal)) => writeln!(out, "#exec signal {signal}")?,
+                _ => writeln!(out, "#exec")?,
+            },
+            Format::Jsonl => {
+                let paths: Vec<_> = paths.iter().map(|p| p.to_string_lossy()).collect();
+                let msg = json!({
+                    "event": "exec",
+                    "command": command,
+                    "paths": paths,
+                    "code": status.code(),
+                    "signal": status.signal(),
+                });
+                writeln!(out, "{msg}")?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Reports the exit code of the command `source`, or the signal that
+    /// killed it.
+    pub fn write_exit(
+        self,
+        out: &mut impl Write,
+        source: &str,
+        status: ExitStatus,
+    ) -> io::Result<()> {
+        match self {
+            Format::Plain => match (status.code(), status.signal()) {
+                (Some(code), _) => writeln!(out, "#exit {code}")?,
+                (_, Some(signal)) => writeln!(out, "#exit signal {signal}")?,
+                _ => writeln!(out, "#exit")?,
+            },
+            Format::Jsonl => {
+                let msg = json!({
+                    "event": "exit",
+                    "source": source,
+                    "code": status.code(),
+                    "signal": status.signal(),
+                });
+                writeln!(out, "{msg}")?;
+            }
+        }
+        out.flush()
+    }
+}
+
+/// Joins the lines of `message`, a plain mode line can't span several.
+fn one_line(message: &str) -> String {
+    let lines = message
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty());
+    lines.collect::<Vec<_>>().join(" ")
+}
+
+/// Writes `value` without quotes, lists separated by commas and maps as
+/// `key:value` pairs, leaving out empty values.
+fn plain(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => values.iter().map(plain).collect::<Vec<_>>().join(","),
+        Value::Object(map) => map
+            .iter()
+            .map(|(key, value)| (key, plain(value)))
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| format!("{key}:{value}"))
+            .collect::<Vec<_>>()
+            .join(" "),
+        value => value.to_string(),
+    }
+}
+
+/// Describes `items` with their score, the char indices of
+/// the matched characters and whether they are marked.
+fn results(
+    snapshot: &Snapshot<Entry>,
+    items: &[Item<Entry>],
+    marks: &[Arc<str>],
+    matcher: &mut Matcher,
+) -> Vec<Value> {
+    let pattern = snapshot.pattern().column_pattern(0);
+    let mut indices = Vec::new();
+    items
+        .iter()
+        .map(|item| {
+            indices.clear();
+            let score = pattern.indices(item.matcher_columns[0].slice(..), matcher, &mut indices);
+            indices.sort_unstable();
+            indices.dedup();
+            json!({
+                "item": &*item.data.item,
+                "root": &*item.data.root,
+                "score": score.unwrap_or(0),
+                "indices": indices,
+                "marked": marks.contains(&item.data.item),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,1153 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::accept::{self, Exec, Resolver};
+use crate::filter;
+use crate::frecency::Frecency;
+use crate::index::{Registry, Stats, Subscription, Update};
+use crate::output::{Entry, Format, Response};
+use crate::source::Source;
+use crate::walk::{self, Kind, WalkOptions};
+use clap::ValueEnum;
+use grep_regex::RegexMatcher;
+use nucleo::{
+    Injector, Item, Matcher, Nucleo, Snapshot,
+    pattern::{CaseMatching, Normalization},
+};
+use serde_json::{Map, Value, json};
+use std::{
+    cmp::Reverse,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long a response may wait for the matcher before partial results are
+/// written.
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(900);
+
+/// Minimum time between two streamed updates.
+const STREAM_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time between two status events with `--progress`.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of top matches reordered by frecency.
+const FRECENCY_WINDOW: u32 = 100;
+
+enum Event {
+    /// A line sent by the client.
+    Input(String),
+    /// The client closed its input.
+    Closed,
+    /// The matcher has new items or finished matching.
+    Notify,
+    /// The walk with this generation finished.
+    WalkDone(u64),
+    /// Items of the walk with this generation were removed.
+    Reset(u64),
+    /// The command of this generation exited.
+    Exited(u64, Arc<str>, ExitStatus),
+    /// The `--exec` command run for these paths exited, without a status if
+    /// it couldn't be waited for.
+    Executed(Arc<str>, Vec<PathBuf>, Option<ExitStatus>),
+}
+
+/// A response owed for the current query.
+struct Pending {
+    deadline: Instant,
+    /// Wait for `deadline` even if the matcher settles earlier.
+    throttled: bool,
+}
+
+/// Settings that apply without a new walk.
+#[derive(Clone)]
+pub struct Options {
+    pub case: CaseMatching,
+    pub normalization: Normalization,
+    pub format: Format,
+    /// Push new results whenever the snapshot changes.
+    pub stream: bool,
+    /// Write status events while walking.
+    pub progress: bool,
+    /// Number of results per response.
+    pub limit: u32,
+    /// Run for accepted paths instead of writing them.
+    pub exec: Option<Exec>,
+    /// Boosts the paths accepted often and recently.
+    pub frecency: Option<Arc<Frecency>>,
+}
+
+pub struct Session<W: Write> {
+    m: Nucleo<Entry>,
+    /// Recomputes match indices of the printed results.
+    matcher: Matcher,
+    registry: Arc<Registry>,
+    /// Options for walking each of `roots`, the path is not used.
+    walk: WalkOptions,
+    roots: Vec<String>,
+    /// Items come from here instead of walking `roots`.
+    source: Option<Source>,
+    /// Items sent with `i:`, added again whenever the matcher restarts.
+    sent: Vec<Arc<str>>,
+    opts: Options,
+    out: W,
+    last_query: String,
+    /// Case matching and normalization `last_query` was parsed with.
+    last_settings: (CaseMatching, Normalization),
+    /// Request id sent with `last_query`, echoed back with its results.
+    last_id: Option<String>,
+    /// Index of the first result written, set by `p:`.
+    offset: u32,
+    /// The results last written and the index of the first one, what `s:`
+    /// indices refer to.
+    shown: (u32, Vec<Arc<str>>),
+    /// Items marked with `m:+`, kept across queries.
+    marks: Vec<Arc<str>>,
+    /// `--exec` commands that have not exited yet.
+    execs: usize,
+    events: Receiver<Event>,
+    /// Set while a `Notify` event is queued, so the walker pushing items
+    /// doesn't flood the channel.
+    notified: Arc<AtomicBool>,
+    pending: Option<Pending>,
+    /// A query was answered, so there is something to stream updates for.
+    queried: bool,
+    last_write: Instant,
+    /// The snapshot changed since the last response was written.
+    unwritten: bool,
+    /// When the next status event is due, if progress is on.
+    next_status: Option<Instant>,
+    /// Write a `done` event after the next response.
+    announce_done: bool,
+    /// All items of the current walk were added.
+    walk_done: bool,
+    /// Sources that have not reported done yet.
+    walks_left: usize,
+    tx: Sender<Event>,
+    /// Feed the matcher with the items of each source.
+    subscriptions: Vec<Subscription>,
+    /// Counts walks so an abandoned one can't report as done.
+    generation: u64,
+}
+
+impl<W: Write> Session<W> {
+    pub fn new(
+        input: impl BufRead + Send + 'static,
+        out: W,
+        registry: Arc<Registry>,
+        walk: WalkOptions,
+        roots: Vec<String>,
+        source: Option<Source>,
+        opts: Options,
+    ) -> Self {
+        let (tx, events) = mpsc::channel();
+        let notified = Arc::new(AtomicBool::new(false));
+        let m: Nucleo<Entry> = Nucleo::new(
+            nucleo::Config::DEFAULT.match_paths(),
+            notifier(tx.clone(), notified.clone()),
+            None,
+            1,
+        );
+        spawn_reader(input, tx.clone());
+
+        let mut session = Self {
+            m,
+            matcher: Matcher::new(nucleo::Config::DEFAULT.match_paths()),
+            registry,
+            walks_left: 0,
+            walk,
+            roots,
+            source,
+            sent: Vec::new(),
+            out,
+            last_query: String::new(),
+            last_settings: Default::default(),
+            last_id: None,
+            offset: 0,
+            shown: (0, Vec::new()),
+            marks: Vec::new(),
+            execs: 0,
+            events,
+            notified,
+            pending: None,
+            queried: false,
+            last_write: Instant::now(),
+            unwritten: false,
+            next_status: opts.progress.then(|| Instant::now() + PROGRESS_INTERVAL),
+            announce_done: false,
+            walk_done: false,
+            opts,
+            tx,
+            subscriptions: Vec::new(),
+            generation: 0,
+        };
+        session.resubscribe();
+        session
+    }
+
+    /// Drops every item and switches to the walk for the current options.
+    fn rewalk(&mut self) {
+        self.walk_done = false;
+        self.track();
+        self.m.restart(true);
+        self.resubscribe();
+    }
+
+    /// Starts over with the remaining items after some were removed. The
+    /// snapshot is kept until the matcher catches up.
+    fn reset(&mut self, generation: u64) {
+        if generation == self.generation {
+            self.m.restart(false);
+            self.resubscribe();
+        }
+    }
+
+    fn resubscribe(&mut self) {
+        self.generation += 1;
+        let sources = self.sources();
+        self.walks_left = sources.len();
+        self.subscriptions = sources
+            .iter()
+            .map(|source| subscribe(&self.registry, source, &self.m, &self.tx, self.generation))
+            .collect();
+        let inj = self.m.injector();
+        for item in &self.sent {
+            inject(&inj, item.clone(), "".into());
+        }
+    }
+
+    fn sources(&self) -> Vec<Source> {
+        Source::all(self.source.as_ref(), &self.walk, &self.roots)
+    }
+
+    /// Handles `c:roots add|remove <dir>`.
+    fn roots(&mut self, arg: Option<&str>) -> Result<(), String> {
+        let (action, dir) = arg
+            .and_then(|arg| arg.split_once(' '))
+            .ok_or("expected add or remove and a directory")?;
+        if self.source.is_some() {
+            return Err("not walking, use c:path to walk a directory".into());
+        }
+        match action {
+            "add" => {
+                if !Path::new(dir).is_dir() {
+                    return Err(format!("{dir} is not a directory"));
+                }
+                if self.roots.iter().any(|root| root == dir) {
+                    return Ok(());
+                }
+                // the other roots keep their items
+                let source = Source::Walk(self.walk.at(dir).into());
+                let sub = subscribe(&self.registry, &source, &self.m, &self.tx, self.generation);
+                self.subscriptions.push(sub);
+                self.roots.push(dir.to_string());
+                self.walks_left += 1;
+                self.walk_done = false;
+                self.track();
+            }
+            "remove" => {
+                let i = self
+                    .roots
+                    .iter()
+                    .position(|root| root == dir)
+                    .ok_or_else(|| format!("{dir} is not a root"))?;
+                if self.roots.len() == 1 {
+                    return Err("can't remove the last root".into());
+                }
+                self.roots.remove(i);
+                self.m.restart(false);
+                self.resubscribe();
+            }
+            _ => return Err("expected add or remove".into()),
+        }
+        Ok(())
+    }
+
+    /// Handles messages until the client exits or closes its input.
+    pub fn run(&mut self) -> io::Result<()> {
+        loop {
+            let respond_at = self.pending.as_ref().map(|p| p.deadline);
+            let event = match respond_at.into_iter().chain(self.next_status).min() {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.events.recv_timeout(timeout) {
+                        Ok(event) => event,
+                        Err(RecvTimeoutError::Timeout) => {
+                            let now = Instant::now();
+                            if respond_at.is_some_and(|d| d <= now) {
+                                self.respond()?;
+                            }
+                            if self.next_status.is_some_and(|t| t <= now) {
+                                self.status()?;
+                            }
+                            continue;
+                        }
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+                None => match self.events.recv() {
+                    Ok(event) => event,
+                    Err(_) => break,
+                },
+            };
+
+            match event {
+                Event::Notify => {
+                    self.notified.store(false, Ordering::Relaxed);
+                    if self.pending.is_some() || self.opts.stream {
+                        self.tick()?;
+                    }
+                }
+                Event::WalkDone(generation) => {
+                    self.walk_done(generation);
+                    self.tick()?;
+                }
+                Event::Reset(generation) => self.reset(generation),
+                Event::Exited(generation, source, status) => {
+                    self.exited(generation, &source, status)?;
+                }
+                Event::Executed(cmd, paths, status) => self.executed(&cmd, &paths, status)?,
+                Event::Closed => break,
+                Event::Input(msg) => {
+                    let mut burst = vec![msg];
+                    let mut closed = false;
+                    while let Ok(event) = self.events.try_recv() {
+                        match event {
+                            Event::Input(msg) => burst.push(msg),
+                            Event::Closed => closed = true,
+                            Event::Notify => self.notified.store(false, Ordering::Relaxed),
+                            Event::WalkDone(generation) => self.walk_done(generation),
+                            Event::Reset(generation) => self.reset(generation),
+                            Event::Exited(generation, source, status) => {
+                                self.exited(generation, &source, status)?;
+                            }
+                            Event::Executed(cmd, paths, status) => {
+                                self.executed(&cmd, &paths, status)?;
+                            }
+                        }
+                    }
+                    for msg in coalesce(burst) {
+                        if !self.handle(&msg)? {
+                            return Ok(());
+                        }
+                    }
+                    if closed {
+                        break;
+                    }
+                    if self.pending.is_some() {
+                        self.tick()?;
+                    }
+                }
+            }
+        }
+        self.flush()
+    }
+
+    /// Answers the query still pending once the input is closed, and
+    /// reports the `--exec` commands still running once they exit.
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some(p) = &self.pending {
+            let s = self.m.tick(10);
+            self.unwritten |= s.changed;
+            if self.settled(s.running) || p.deadline <= Instant::now() {
+                self.respond()?;
+                break;
+            }
+        }
+        while self.execs > 0 {
+            match self.events.recv() {
+                Ok(Event::Executed(cmd, paths, status)) => self.executed(&cmd, &paths, status)?,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Handles a single message, returning `false` once the client asks to
+    /// exit.
+    fn handle(&mut self, msg: &str) -> io::Result<bool> {
+        if let Some(cmd) = msg.strip_prefix("c:") {
+            if cmd == "Exit" {
+                return Ok(false);
+            }
+            if cmd == "errors" {
+                let errors: Vec<_> = self.subscriptions.iter().flat_map(|s| s.errors()).collect();
+                self.opts.format.write_errors(&mut self.out, &errors)?;
+                return Ok(true);
+            }
+            if cmd == "status" {
+                let s = self.m.tick(10);
+                self.unwritten |= s.changed;
+                let (stats, state) = (self.stats(), self.state());
+                self.opts
+                    .format
+                    .write_status(&mut self.out, &stats, Some(&state))?;
+                return Ok(true);
+            }
+            if cmd == "types" {
+                let types = walk::type_definitions(&self.walk);
+                self.opts.format.write_types(&mut self.out, &types)?;
+                return Ok(true);
+            }
+            if let Err(e) = self.command(cmd) {
+                self.error(&format!("c:{cmd}: {e}"))?;
+                return Ok(true);
+            }
+            self.request();
+        } else if let Some(regex) = msg.strip_prefix("g:") {
+            if let Err(e) = self.grep(regex) {
+                self.error(&format!("g:{regex}: {e}"))?;
+                return Ok(true);
+            }
+            self.request();
+        } else if let Some(query) = parse_query(msg) {
+            let (case, normalization) = match self.query_settings(query.flags) {
+                Ok(settings) => settings,
+                Err(e) => {
+                    self.error(&format!("{msg}: {e}"))?;
+                    return Ok(true);
+                }
+            };
+            self.last_id = query.id.map(str::to_string);
+            self.reparse(query.text, case, normalization);
+            self.request();
+        } else if let Some(item) = msg.strip_prefix("i:") {
+            let item: Arc<str> = item.into();
+            inject(&self.m.injector(), item.clone(), "".into());
+            self.sent.push(item);
+        } else if let Some(arg) = msg.strip_prefix("s:") {
+            let accepted = self.item(arg).and_then(|item| {
+                let path = accept::resolve(&item).map_err(|e| e.to_string())?;
+                Ok((item, path))
+            });
+            match accepted {
+                Ok((item, path)) => self.accept(&[item], vec![path])?,
+                Err(e) => self.error(&format!("s:{arg}: {e}"))?,
+            }
+        } else if let Some(arg) = msg.strip_prefix("m:") {
+            if arg == "accept" {
+                match self.marked_paths() {
+                    Ok(paths) => self.accept(&self.marks.clone(), paths)?,
+                    Err(e) => self.error(&format!("m:{arg}: {e}"))?,
+                }
+            } else if let Err(e) = self.mark(arg) {
+                self.error(&format!("m:{arg}: {e}"))?;
+            } else {
+                // written again with the marks flagged
+                self.unwritten = true;
+                self.request();
+            }
+        } else if let Some(offset) = msg.strip_prefix("p:") {
+            match offset.trim().parse() {
+                Ok(offset) => self.page(offset)?,
+                Err(e) => self.error(&format!("p:{offset}: {e}"))?,
+            }
+        }
+        Ok(true)
+    }
+
+    /// Applies a `c:` command to the running session.
+    fn command(&mut self, cmd: &str) -> Result<(), String> {
+        let (name, arg) = match cmd.split_once(' ') {
+            Some((name, arg)) => (name, Some(arg.trim())),
+            None => (cmd, None),
+        };
+        match name {
+            "stream" => {
+                self.opts.stream = toggle(self.opts.stream, arg)?;
+                return Ok(());
+            }
+            "progress" => {
+                self.opts.progress = toggle(self.opts.progress, arg)?;
+                self.next_status = None;
+                self.track();
+                return Ok(());
+            }
+            "frecency" => {
+                let on = toggle(self.opts.frecency.is_some(), arg)?;
+                self.opts.frecency = match (on, self.opts.frecency.take()) {
+                    (true, Some(frecency)) => Some(frecency),
+                    (true, None) => Some(Arc::new(Frecency::load())),
+                    (false, _) => None,
+                };
+                // written again in the new order
+                self.unwritten = true;
+                return Ok(());
+            }
+            "exec" | "exec_batch" => {
+                let cmd = arg.filter(|cmd| !cmd.is_empty()).map(str::to_string);
+                self.opts.exec = match name {
+                    "exec" => cmd.map(Exec::Each),
+                    _ => cmd.map(Exec::Batch),
+                };
+                return Ok(());
+            }
+            "limit" => {
+                let limit = arg.ok_or("missing number")?;
+                self.opts.limit = limit.parse().map_err(|e| format!("{limit}: {e}"))?;
+                // written again with the new number of results
+                self.unwritten = true;
+                return Ok(());
+            }
+            "hidden" => self.walk.hidden = toggle(self.walk.hidden, arg)?,
+            "no_ignore" => self.walk.no_ignore = toggle(self.walk.no_ignore, arg)?,
+            "follow" => self.walk.follow_symlinks = toggle(self.walk.follow_symlinks, arg)?,
+            "path" => {
+                let path = arg.filter(|p| !p.is_empty()).ok_or("missing directory")?;
+                if !Path::new(path).is_dir() {
+                    return Err(format!("{path} is not a directory"));
+                }
+                self.roots = vec![path.to_string()];
+                self.source = None;
+            }
+            "roots" => return self.roots(arg),
+            "glob" => self.walk.globs = globs(arg)?,
+            "exclude" => self.walk.excludes = globs(arg)?,
+            "kind" => {
+                self.walk.kinds = words(arg)
+                    .iter()
+                    .map(|kind| Kind::from_str(kind, true))
+                    .collect::<Result<_, _>>()?;
+            }
+            "size" => {
+                self.walk.sizes = words(arg)
+                    .iter()
+                    .map(|size| filter::parse_size(size))
+                    .collect::<Result<_, _>>()?;
+            }
+            "changed_within" => self.walk.changed_within = duration(arg)?,
+            "changed_before" => self.walk.changed_before = duration(arg)?,
+            "owner" => {
+                self.walk.owner = arg
+                    .filter(|owner| !owner.is_empty())
+                    .map(filter::parse_owner)
+                    .transpose()?;
+            }
+            "max_depth" => self.walk.max_depth = depth(arg)?,
+            "min_depth" => self.walk.min_depth = depth(arg)?,
+            "one_file_system" => {
+                self.walk.one_file_system = toggle(self.walk.one_file_system, arg)?;
+            }
+            "max_filesize" => {
+                self.walk.max_filesize = arg
+                    .filter(|size| !size.is_empty())
+                    .map(filter::parse_bytes)
+                    .transpose()?;
+            }
+            "type" | "type_not" | "type_add" => {
+                let mut walk = self.walk.clone();
+                match name {
+                    "type" => walk.types = words(arg),
+                    "type_not" => walk.types_not = words(arg),
+                    _ => {
+                        let def = arg.ok_or("missing definition")?;
+                        walk.type_defs
+                            .push(walk::parse_type_def(def).map_err(|e| e.to_string())?);
+                    }
+                }
+                walk::file_types(&walk).map_err(|e| e.to_string())?;
+                self.walk = walk;
+            }
+            "source" => {
+                let cmd = arg.filter(|c| !c.is_empty()).ok_or("missing command")?;
+                self.source = Some(Source::Command(cmd.to_string()));
+            }
+            "case" => {
+                self.opts.case = match arg {
+                    Some("smart") => CaseMatching::Smart,
+                    Some("ignore") => CaseMatching::Ignore,
+                    Some("respect") => CaseMatching::Respect,
+                    _ => return Err("expected smart, ignore or respect".into()),
+                };
+                let query = self.last_query.clone();
+                self.reparse(&query, self.opts.case, self.opts.normalization);
+                return Ok(());
+            }
+            "normalize" => {
+                let on = toggle(self.opts.normalization == Normalization::Smart, arg)?;
+                self.opts.normalization = match on {
+                    true => Normalization::Smart,
+                    false => Normalization::Never,
+                };
+                let query = self.last_query.clone();
+                self.reparse(&query, self.opts.case, self.opts.normalization);
+                return Ok(());
+            }
+            _ => return Err("unknown command".into()),
+        }
+        self.rewalk();
+        Ok(())
+    }
+
+    /// Resolves the per query flags of `q/flags:` on top of the session
+    /// settings.
+    fn query_settings(&self, flags: &str) -> Result<(CaseMatching, Normalization), String> {
+        let (mut case, mut normalization) = (self.opts.case, self.opts.normalization);
+        for flag in flags.chars() {
+            match flag {
+                's' => case = CaseMatching::Smart,
+                'i' => case = CaseMatching::Ignore,
+                'r' => case = CaseMatching::Respect,
+                'n' => normalization = Normalization::Smart,
+                'N' => normalization = Normalization::Never,
+                _ => return Err(format!("unknown flag {flag}")),
+            }
+        }
+        Ok((case, normalization))
+    }
+
+    /// Updates the pattern, appending to the previous one when possible.
+    fn reparse(&mut self, query: &str, case: CaseMatching, normalization: Normalization) {
+        let settings = (case, normalization);
+        if query == self.last_query && settings == self.last_settings {
+            return;
+        }
+        let append = settings == self.last_settings && query.starts_with(&self.last_query);
+        self.m
+            .pattern
+            .reparse(0, query, case, normalization, append);
+        self.last_query = query.to_string();
+        self.last_settings = settings;
+        self.offset = 0;
+    }
+
+    /// Switches to searching file contents for `regex`, or back to listing
+    /// paths if it is empty.
+    fn grep(&mut self, regex: &str) -> Result<(), String> {
+        let content = match regex {
+            "" => None,
+            _ => {
+                RegexMatcher::new(regex).map_err(|e| e.to_string())?;
+                Some(regex.to_string())
+            }
+        };
+        if content != self.walk.content {
+            self.walk.content = content;
+            self.rewalk();
+        }
+        Ok(())
+    }
+
+    /// Schedules a response for the current query. Any response still owed
+    /// for an older query is replaced by this one.
+    fn request(&mut self) {
+        self.queried = true;
+        self.pending = Some(Pending {
+            deadline: Instant::now() + RESPONSE_TIMEOUT,
+            throttled: false,
+        });
+    }
+
+    /// Writes the results starting at `offset` from the current snapshot
+    /// right away.
+    fn page(&mut self, offset: u32) -> io::Result<()> {
+        self.m.tick(10);
+        self.offset = offset;
+        self.unwritten = true;
+        self.pending = Some(Pending {
+            deadline: Instant::now(),
+            throttled: false,
+        });
+        self.respond()
+    }
+
+    /// In stream mode, schedules the final results of the current walk
+    /// followed by a `done` event.
+    fn walk_done(&mut self, generation: u64) {
+        if generation != self.generation {
+            return;
+        }
+        self.walks_left = self.walks_left.saturating_sub(1);
+        if self.walks_left > 0 || self.walk_done {
+            return;
+        }
+        self.walk_done = true;
+        if self.opts.progress {
+            self.next_status = Some(Instant::now());
+        }
+        if self.opts.stream && self.queried {
+            self.announce_done = true;
+            self.request();
+        }
+    }
+
+    /// Schedules status events until the walk is done, if progress is on.
+    fn track(&mut self) {
+        if self.opts.progress && self.next_status.is_none() {
+            self.next_status = Some(Instant::now() + PROGRESS_INTERVAL);
+        }
+    }
+
+    /// Writes a status event, and schedules the next one unless the walk is
+    /// done.
+    fn status(&mut self) -> io::Result<()> {
+        self.next_status = None;
+        if !self.walk_done {
+            self.track();
+        }
+        let stats = self.stats();
+        self.opts.format.write_status(&mut self.out, &stats, None)
+    }
+
+    /// Progress of every source together.
+    fn stats(&self) -> Stats {
+        let mut total = Stats {
+            items: 0,
+            dirs: 0,
+            errors: 0,
+            elapsed: Duration::ZERO,
+            done: self.walk_done,
+        };
+        for stats in self.subscriptions.iter().map(Subscription::stats) {
+            total.items += stats.items;
+            total.dirs += stats.dirs;
+            total.errors += stats.errors;
+            total.elapsed = total.elapsed.max(stats.elapsed);
+        }
+        total
+    }
+
+    /// The matcher, query, roots and settings, for `c:status`.
+    fn state(&self) -> Map<String, Value> {
+        let snapshot = self.m.snapshot();
+        let w = &self.walk;
+        let secs = |d: Option<Duration>| d.map(|d| format!("{}s", d.as_secs()));
+        let config = json!({
+            "hidden": w.hidden,
+            "no_ignore": w.no_ignore,
+            "follow": w.follow_symlinks,
+            "content": w.content,
+            "glob": w.globs,
+            "exclude": w.excludes,
+            "type": w.types,
+            "type_not": w.types_not,
+            "type_add": w.type_defs,
+            "kind": w.kinds.iter()
+                .filter_map(|kind| Some(kind.to_possible_value()?.get_name().to_string()))
+                .collect::<Vec<_>>(),
+            "size": w.sizes.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "changed_within": secs(w.changed_within),
+            "changed_before": secs(w.changed_before),
+            "owner": w.owner.map(|owner| owner.to_string()),
+            "max_depth": w.max_depth,
+            "min_depth": w.min_depth,
+            "one_file_system": w.one_file_system,
+            "max_filesize": w.max_filesize,
+            "case": match self.opts.case {
+                CaseMatching::Ignore => "ignore",
+                CaseMatching::Respect => "respect",
+                _ => "smart",
+            },
+            "normalize": self.opts.normalization == Normalization::Smart,
+            "stream": self.opts.stream,
+            "progress": self.opts.progress,
+            "frecency": self.opts.frecency.is_some(),
+            "limit": self.opts.limit,
+        });
+        let state = json!({
+            "matched": snapshot.matched_item_count(),
+            "total": snapshot.item_count(),
+            "query": self.last_query,
+            "roots": self.roots,
+            "source": self.source.as_ref().map(Source::name),
+            "config": config,
+        });
+        match state {
+            Value::Object(state) => state,
+            _ => unreachable!(),
+        }
+    }
+
+    /// The item `arg` of `s:` and `m:` stands for. An index counts from 0
+    /// like `p:` and must have been written in the last response, anything
+    /// else is the item itself.
+    fn item(&self, arg: &str) -> Result<Arc<str>, String> {
+        let Ok(i) = arg.parse::<u32>() else {
+            return Ok(arg.into());
+        };
+        let (start, shown) = &self.shown;
+        let item = i.checked_sub(*start).and_then(|i| shown.get(i as usize));
+        item.cloned()
+            .ok_or_else(|| format!("result {i} was not written"))
+    }
+
+    /// Handles `m:+<item>`, `m:-<item>` and `m:clear`.
+    fn mark(&mut self, arg: &str) -> Result<(), String> {
+        if arg == "clear" {
+            self.marks.clear();
+        } else if let Some(arg) = arg.strip_prefix('+') {
+            let item = self.item(arg)?;
+            if !self.marks.contains(&item) {
+                self.marks.push(item);
+            }
+        } else if let Some(arg) = arg.strip_prefix('-') {
+            let item = self.item(arg)?;
+            self.marks.retain(|mark| *mark != item);
+        } else {
+            return Err("expected +<item>, -<item>, clear or accept".into());
+        }
+        Ok(())
+    }
+
+    /// The canonical paths of the marked items, in the order they were
+    /// marked.
+    fn marked_paths(&self) -> Result<Vec<PathBuf>, String> {
+        if self.marks.is_empty() {
+            return Err("nothing is marked".into());
+        }
+        self.marks
+            .iter()
+            .map(|item| accept::resolve(item).map_err(|e| format!("{item}: {e}")))
+            .collect()
+    }
+
+    /// Writes the accepted paths of `items`, or runs `--exec` for them.
+    fn accept(&mut self, items: &[Arc<str>], paths: Vec<PathBuf>) -> io::Result<()> {
+        if let Some(frecency) = &self.opts.frecency {
+            frecency.add(&self.frecency_keys(items, &paths));
+        }
+        match self.opts.exec.clone() {
+            None => self.opts.format.write_accept(&mut self.out, &paths),
+            Some(Exec::Each(cmd)) => {
+                for path in paths {
+                    self.exec(&cmd, vec![path])?;
+                }
+                Ok(())
+            }
+            Some(Exec::Batch(cmd)) => self.exec(&cmd, paths),
+        }
+    }
+
+    /// The paths `ranked` looks `items` up by, which only resolves the
+    /// symbolic links of their root. Falls back to the canonical `paths`.
+    fn frecency_keys(&self, items: &[Arc<str>], paths: &[PathBuf]) -> Vec<PathBuf> {
+        let mut resolver = Resolver::new(self.content());
+        let keys = items.iter().zip(paths).map(|(item, path)| {
+            // the root the item was found under, as the matcher has it
+            let root = match &self.source {
+                Some(source) => source.name(),
+                None => self
+                    .roots
+                    .iter()
+                    .filter(|root| Path::new(&**item).starts_with(root))
+                    .max_by_key(|root| root.len())
+                    .cloned()
+                    .unwrap_or_default(),
+            };
+            resolver.path(item, &root).unwrap_or_else(|| path.clone())
+        });
+        keys.collect()
+    }
+
+    /// Whether items are content matches, `path:line:col:text`.
+    fn content(&self) -> bool {
+        self.source.is_none() && self.walk.content.is_some()
+    }
+
+    /// Runs `cmd` for `paths`, its exit status is reported once it exits.
+    fn exec(&mut self, cmd: &str, paths: Vec<PathBuf>) -> io::Result<()> {
+        let mut child = match accept::spawn(cmd, &paths) {
+            Ok(child) => child,
+            Err(e) => return self.error(&format!("{cmd}: {e}")),
+        };
+        self.execs += 1;
+        let tx = self.tx.clone();
+        let cmd: Arc<str> = cmd.into();
+        thread::spawn(move || {
+            let status = child.wait();
+            if let Err(e) = &status {
+                eprintln!("gf: {cmd}: {e}");
+            }
+            let _ = tx.send(Event::Executed(cmd, paths, status.ok()));
+        });
+        Ok(())
+    }
+
+    /// Tells the client a message could not be handled.
+    fn error(&mut self, message: &str) -> io::Result<()> {
+        self.opts.format.write_error(&mut self.out, message)
+    }
+
+    fn executed(
+        &mut self,
+        cmd: &str,
+        paths: &[PathBuf],
+        status: Option<ExitStatus>,
+    ) -> io::Result<()> {
+        self.execs -= 1;
+        match status {
+            Some(status) => self
+                .opts
+                .format
+                .write_exec(&mut self.out, cmd, paths, status),
+            None => Ok(()),
+        }
+    }
+
+    /// Reports the exit status of the current command.
+    fn exited(&mut self, generation: u64, source: &str, status: ExitStatus) -> io::Result<()> {
+        if generation != self.generation {
+            return Ok(());
+        }
+        self.opts.format.write_exit(&mut self.out, source, status)
+    }
+
+    /// Lets the matcher catch up and responds once it has settled or the
+    /// response is due. In stream mode a changed snapshot schedules an update.
+    fn tick(&mut self) -> io::Result<()> {
+        let s = self.m.tick(10);
+        self.unwritten |= s.changed;
+        if self.pending.is_none() && s.changed && self.opts.stream && self.queried {
+            self.pending = Some(Pending {
+                deadline: self.last_write + STREAM_INTERVAL,
+                throttled: true,
+            });
+        }
+        let Some(p) = &self.pending else {
+            return Ok(());
+        };
+        if (self.settled(s.running) && !p.throttled) || p.deadline <= Instant::now() {
+            self.respond()?;
+        }
+        Ok(())
+    }
+
+    /// The matcher is done, and has items to match unless the walk found
+    /// none. Right after a restart it settles before the walk adds anything.
+    fn settled(&self, running: bool) -> bool {
+        !running && (self.walk_done || self.m.snapshot().item_count() > 0)
+    }
+
+    /// Writes the top results for the pending response.
+    fn respond(&mut self) -> io::Result<()> {
+        if self.pending.take().is_none() {
+            return Ok(());
+        }
+        let snapshot = self.m.snapshot();
+        let matched = snapshot.matched_item_count();
+        let start = self.offset.min(matched);
+        let end = start.saturating_add(self.opts.limit).min(matched);
+        let items = match &self.opts.frecency {
+            Some(frecency) if !frecency.is_empty() => {
+                let content = self.content();
+                let mut items = ranked(snapshot, frecency, end, content, &mut self.matcher);
+                items.split_off(start as usize)
+            }
+            _ => snapshot.matched_items(start..end).collect(),
+        };
+        self.shown = (
+            start,
+            items.iter().map(|item| item.data.item.clone()).collect(),
+        );
+        let res = Response {
+            id: self.last_id.as_deref(),
+            query: &self.last_query,
+            snapshot,
+            offset: start,
+            items,
+            marks: &self.marks,
+            changed: std::mem::take(&mut self.unwritten),
+            running: !self.walk_done,
+        };
+        self.opts
+            .format
+            .write(&mut self.out, &res, &mut self.matcher)?;
+        self.last_write = Instant::now();
+        if std::mem::take(&mut self.announce_done) {
+            self.opts
+                .format
+                .write_done(&mut self.out, snapshot.item_count())?;
+        }
+        Ok(())
+    }
+}
+
+/// The first `end` matches once the best ones are boosted by frecency.
+/// Only the top matches are reordered, so paging stays cheap.
+/// `content` tells whether the items are content matches.
+fn ranked<'a>(
+    snapshot: &'a Snapshot<Entry>,
+    frecency: &Frecency,
+    end: u32,
+    content: bool,
+    matcher: &mut Matcher,
+) -> Vec<Item<'a, Entry>> {
+    let window = end.max(FRECENCY_WINDOW).min(snapshot.matched_item_count());
+    let pattern = snapshot.pattern();
+    let mut resolver = Resolver::new(content);
+    let mut items: Vec<_> = snapshot
+        .matched_items(..window)
+        .map(|item| {
+            let score = pattern.score(item.matcher_columns, matcher).unwrap_or(0);
+            let path = resolver.path(&item.data.item, &item.data.root);
+            let boost = path.map_or(0, |path| frecency.boost(&path));
+            (score + boost, item)
+        })
+        .collect();
+    // stable, equal scores keep the matcher's order
+    items.sort_by_key(|(score, _)| Reverse(*score));
+    items.truncate(end as usize);
+    items.into_iter().map(|(_, item)| item).collect()
+}
+
+/// Feeds `m` from the index for `source`, forwarding its updates as events.
+fn subscribe(
+    registry: &Registry,
+    source: &Source,
+    m: &Nucleo<Entry>,
+    tx: &Sender<Event>,
+    generation: u64,
+) -> Subscription {
+    let inj = m.injector();
+    let root: Arc<str> = source.name().into();
+    let name = root.clone();
+    let push = move |item| inject(&inj, item, root.clone());
+    let tx = tx.clone();
+    registry.subscribe(source, push, move |update| {
+        let _ = tx.send(match update {
+            Update::Done => Event::WalkDone(generation),
+            Update::Reset => Event::Reset(generation),
+            Update::Exited(status) => Event::Exited(generation, name.clone(), status),
+        });
+    })
+}
+
+fn inject(inj: &Injector<Entry>, item: Arc<str>, root: Arc<str>) {
+    inj.push(Entry { item, root }, |e, cols| {
+        cols[0] = e.item.as_ref().into()
+    });
+}
+
+/// Parses the space separated globs of `c:glob` and `c:exclude`.
+fn globs(arg: Option<&str>) -> Result<Vec<String>, String> {
+    words(arg)
+        .iter()
+        .map(|glob| walk::parse_glob(glob).map_err(|e| e.to_string()))
+        .collect()
+}
+
+/// Parses the depth of `c:max_depth` and `c:min_depth`, nothing removes the
+/// limit.
+fn depth(arg: Option<&str>) -> Result<Option<usize>, String> {
+    let arg = arg.filter(|arg| !arg.is_empty());
+    arg.map(|n| n.parse().map_err(|e| format!("{n}: {e}")))
+        .transpose()
+}
+
+/// Parses the duration of `c:changed_*`, nothing removes the filter.
+fn duration(arg: Option<&str>) -> Result<Option<Duration>, String> {
+    let arg = arg.filter(|arg| !arg.is_empty());
+    arg.map(filter::parse_duration).transpose()
+}
+
+fn words(arg: Option<&str>) -> Vec<String> {
+    let words = arg.unwrap_or_default().split_whitespace();
+    words.map(str::to_string).collect()
+}
+
+/// Builds the matcher's notify callback. Sends at most one `Notify` until
+/// the session has handled it.
+fn notifier(tx: Sender<Event>, notified: Arc<AtomicBool>) -> Arc<dyn Fn() + Send + Sync> {
+    Arc::new(move || {
+        if !notified.swap(true, Ordering::Relaxed) {
+            let _ = tx.send(Event::Notify);
+        }
+    })
+}
+
+/// Reads lines from `input` on a background thread so the session never
+/// blocks on the client.
+fn spawn_reader(input: impl BufRead + Send + 'static, tx: Sender<Event>) {
+    thread::spawn(move || {
+        for line in input.lines() {
+            let Ok(line) = line else { break };
+            if tx.send(Event::Input(line)).is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(Event::Closed);
+    });
+}
+
+/// Drops every query that is directly followed by another one, only the
+/// newest of a burst of keystrokes is worth matching.
+fn coalesce(burst: Vec<String>) -> Vec<String> {
+    let mut msgs: Vec<String> = Vec::with_capacity(burst.len());
+    for msg in burst {
+        if parse_query(&msg).is_some()
+            && msgs.last().is_some_and(|last| parse_query(last).is_some())
+        {
+            msgs.pop();
+        }
+        msgs.push(msg);
+    }
+    msgs
+}
+
+/// A `q[#id][/flags]:text` message.
+struct Query<'a> {
+    id: Option<&'a str>,
+    flags: &'a str,
+    text: &'a str,
+}
+
+fn parse_query(msg: &str) -> Option<Query<'_>> {
+    let (header, text) = msg.strip_prefix('q')?.split_once(':')?;
+    let (id, flags) = header.split_once('/').unwrap_or((header, ""));
+    let id = match id {
+        "" => None,
+        // `q#:foo` has no id, an empty `#` line would be ambiguous
+        id => Some(id.strip_prefix('#')?).filter(|id| !id.is_empty()),
+    };
+    Some(Query { id, flags, text })
+}
+
+/// Parses an `on`/`off` argument, flipping `current` when none is given.
+fn toggle(current: bool, arg: Option<&str>) -> Result<bool, String> {
+    match arg {
+        None | Some("") => Ok(!current),
+        Some("on") => Ok(true),
+        Some("off") => Ok(false),
+        Some(other) => Err(format!("expected on or off, got {other}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(msg: &str) -> Option<(Option<&str>, &str, &str)> {
+        parse_query(msg).map(|q| (q.id, q.flags, q.text))
+    }
+
+    #[test]
+    fn parse_query_header() {
+        assert_eq!(query("q:foo"), Some((None, "", "foo")));
+        assert_eq!(query("q/i:foo"), Some((None, "i", "foo")));
+        assert_eq!(query("q#42:foo"), Some((Some("42"), "", "foo")));
+        assert_eq!(query("q#1/i:a:b"), Some((Some("1"), "i", "a:b")));
+        assert_eq!(query("q#:x"), Some((None, "", "x")));
+        assert_eq!(query("q42:foo"), None);
+        assert_eq!(query("qfoo"), None);
+        assert_eq!(query("c:limit 5"), None);
+    }
+
+    #[test]
+    fn coalesce_keeps_last_of_consecutive_queries() {
+        let burst = ["q:a", "q#1:ab", "c:hidden", "q:abc", "q/i:abcd", "s:0"];
+        let msgs = coalesce(burst.map(String::from).to_vec());
+        assert_eq!(msgs, ["q#1:ab", "c:hidden", "q/i:abcd", "s:0"]);
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::walk::{Sink, WalkOptions, walk};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::process::CommandExt,
+    },
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+/// How often a running command checks whether it was canceled.
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+
+/// Where the items of an index come from.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Source {
+    Walk(Box<WalkOptions>),
+    /// Lines of a file.
+    File(PathBuf),
+    /// Lines read from a file descriptor inherited from the parent.
+    Fd(RawFd),
+    /// Lines printed by a shell command.
+    Command(String),
+}
+
+impl Source {
+    /// What a session reads: `source` if set, otherwise a walk of each root.
+    pub fn all(source: Option<&Source>, walk: &WalkOptions, roots: &[String]) -> Vec<Source> {
+        match source {
+            Some(source) => vec![source.clone()],
+            None => roots
+                .iter()
+                .map(|root| Source::Walk(walk.at(root).into()))
+                .collect(),
+        }
+    }
+
+    /// Shown as the root of every item.
+    pub fn name(&self) -> String {
+        match self {
+            Source::Walk(opts) => opts.path.clone(),
+            Source::File(path) => path.display().to_string(),
+            Source::Fd(fd) => format!("fd:{fd}"),
+            Source::Command(cmd) => cmd.clone(),
+        }
+    }
+
+    /// Pushes every item into `sink` until there are no more or `cancel` is
+    /// set. Returns the exit status of a command.
+    pub fn read(&self, cancel: &AtomicBool, sink: &dyn Sink) -> Option<ExitStatus> {
+        let input: Box<dyn Read> = match self {
+            Source::Walk(opts) => {
+                walk(opts, cancel, sink);
+                return None;
+            }
+            Source::File(path) => match File::open(path) {
+                Ok(f) => Box::new(f),
+                Err(e) => {
+                    eprintln!("gf: {}: {e}", path.display());
+                    return None;
+                }
+            },
+            // SAFETY: the fd is only read here, by the one index for it
+            Source::Fd(fd) => Box::new(unsafe { File::from_raw_fd(*fd) }),
+            Source::Command(cmd) => return run(cmd, cancel, sink),
+        };
+        if let Err(e) = lines(BufReader::new(input), cancel, sink) {
+            eprintln!("gf: {}: {e}", self.name());
+        }
+        None
+    }
+}
+
+/// Runs `cmd` with `sh -c`, pushing every line it prints. The command and
+/// everything it started are terminated if `cancel` is set before it exits.
+fn run(cmd: &str, cancel: &AtomicBool, sink: &dyn Sink) -> Option<ExitStatus> {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            eprintln!("gf: {cmd}: {e}");
+            return None;
+        }
+    };
+    let stdout = child.stdout.take()?;
+    // read on another thread, a command printing nothing would block the check
+    let read = thread::scope(|s| {
+        let reader = s.spawn(|| lines(BufReader::new(stdout), cancel, sink));
+        let mut killed = false;
+        while !reader.is_finished() {
+            if !killed && cancel.load(Ordering::Relaxed) {
+                killed = true;
+                // SAFETY: plain syscall, the group was created for this child
+                unsafe { libc::kill(-(child.id() as i32), libc::SIGTERM) };
+            }
+            thread::sleep(CANCEL_POLL);
+        }
+        reader.join().unwrap()
+    });
+    if let Err(e) = read {
+        eprintln!("gf: {cmd}: {e}");
+    }
+    match child.wait() {
+        Ok(status) => Some(status),
+        Err(e) => {
+            eprintln!("gf: {cmd}: {e}");
+            None
+        }
+    }
+}
+
+/// Pushes every non-empty line of `input`.
+fn lines(mut input: impl BufRead, cancel: &AtomicBool, sink: &dyn Sink) -> io::Result<()> {
+    let mut buf = Vec::new();
+    while !cancel.load(Ordering::Relaxed) {
+        buf.clear();
+        if input.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.trim_end_matches(['\r', '\n']);
+        if !line.is_empty() {
+            sink.push(line.into());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,441 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::filter::{self, Owner, Size};
+use clap::ValueEnum;
+use grep_matcher::Matcher;
+use grep_regex::RegexMatcher;
+use grep_searcher::{BinaryDetection, Searcher, SearcherBuilder, sinks::Lossy};
+use ignore::{
+    DirEntry, WalkBuilder, WalkState,
+    overrides::{Override, OverrideBuilder},
+    types::{FileTypeDef, Types, TypesBuilder},
+};
+use std::{
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, SystemTime},
+};
+
+/// Settings that require a new walk when changed.
+#[derive(Clone, Default, PartialEq, Eq, Hash, Debug)]
+pub struct WalkOptions {
+    pub path: String,
+    /// The root a subtree walk is below, globs are relative to it.
+    pub subtree_of: Option<String>,
+    pub no_ignore: bool,
+    pub hidden: bool,
+    pub follow_symlinks: bool,
+    /// Search inside files for this regex instead of listing paths.
+    pub content: Option<String>,
+    /// Only list files matching one of these globs, if any.
+    pub globs: Vec<String>,
+    /// Skip entries matching these globs.
+    pub excludes: Vec<String>,
+    /// Only list files of these types, if any.
+    pub types: Vec<String>,
+    /// Skip files of these types.
+    pub types_not: Vec<String>,
+    /// Extra type definitions, `name:glob` like `rg --type-add`.
+    pub type_defs: Vec<String>,
+    /// Only list entries of these kinds, if any.
+    pub kinds: Vec<Kind>,
+    /// Only list files within all of these sizes.
+    pub sizes: Vec<Size>,
+    /// Only list entries modified at most this long before the walk.
+    pub changed_within: Option<Duration>,
+    /// Only list entries modified at least this long before the walk.
+    pub changed_before: Option<Duration>,
+    pub owner: Option<Owner>,
+    /// Don't descend further below the root than this.
+    pub max_depth: Option<usize>,
+    /// Only list entries at least this deep, the root is at depth 0.
+    pub min_depth: Option<usize>,
+    /// Don't cross into other file systems, like mounts or `/proc`.
+    pub one_file_system: bool,
+    /// Skip files larger than this many bytes.
+    pub max_filesize: Option<u64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, ValueEnum)]
+pub enum Kind {
+    /// Regular files
+    #[value(name = "f", alias = "file")]
+    File,
+    /// Directories
+    #[value(name = "d", alias = "directory")]
+    Directory,
+    /// Symbolic links
+    #[value(name = "l", alias = "symlink")]
+    Symlink,
+    /// Executable files
+    #[value(name = "x", alias = "executable")]
+    Executable,
+}
+
+impl Kind {
+    fn matches(self, entry: &DirEntry) -> bool {
+        let is_file = entry.file_type().is_some_and(|t| t.is_file());
+        match self {
+            Kind::File => is_file,
+            Kind::Directory => entry.file_type().is_some_and(|t| t.is_dir()),
+            Kind::Symlink => entry.path_is_symlink(),
+            Kind::Executable => {
+                is_file
+                    && entry
+                        .metadata()
+                        .is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+            }
+        }
+    }
+}
+
+impl WalkOptions {
+    /// The same options walking `root`.
+    pub fn at(&self, root: &str) -> WalkOptions {
+        WalkOptions {
+            path: root.to_string(),
+            subtree_of: None,
+            ..self.clone()
+        }
+    }
+
+    /// The same options walking `path`, which is `depth` levels below the
+    /// root. Nothing if it is too deep to be walked.
+    pub fn subtree(&self, path: &str, depth: usize) -> Option<WalkOptions> {
+        let max_depth = match self.max_depth {
+            Some(max) => Some(max.checked_sub(depth)?),
+            None => None,
+        };
+        Some(WalkOptions {
+            max_depth,
+            min_depth: self.min_depth.map(|min| min.saturating_sub(depth)),
+            subtree_of: Some(self.root().to_string()),
+            ..self.at(path)
+        })
+    }
+
+    /// The directory the walk started from, above `path` for a subtree.
+    fn root(&self) -> &str {
+        self.subtree_of.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Checks the metadata filters, `now` is when the walk started.
+    fn metadata_matches(&self, entry: &DirEntry, now: SystemTime) -> bool {
+        if self.sizes.is_empty()
+            && self.changed_within.is_none()
+            && self.changed_before.is_none()
+            && self.owner.is_none()
+        {
+            return true;
+        }
+        let Ok(metadata) = entry.metadata() else {
+            return false;
+        };
+        let age = filter::age(&metadata, now);
+        (self.sizes.is_empty() || metadata.is_file())
+            && self.sizes.iter().all(|size| size.matches(&metadata))
+            && self
+                .changed_within
+                .is_none_or(|d| age.is_some_and(|age| age <= d))
+            && self
+                .changed_before
+                .is_none_or(|d| age.is_some_and(|age| age >= d))
+            && self.owner.is_none_or(|owner| owner.matches(&metadata))
+    }
+}
+
+/// An entry the walk could not read.
+#[derive(Clone)]
+pub struct WalkError {
+    /// `permission_denied`, `not_found` (like broken symlinks), `loop`, `io`
+    /// or `other`.
+    pub kind: &'static str,
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+impl From<ignore::Error> for WalkError {
+    fn from(err: ignore::Error) -> Self {
+        let message = err.to_string();
+        let (mut err, mut path) = (&err, None);
+        loop {
+            match err {
+                ignore::Error::WithPath {
+                    path: p,
+                    err: inner,
+                } => {
+                    path = path.or(Some(p.clone()));
+                    err = inner;
+                }
+                ignore::Error::WithDepth { err: inner, .. } => err = inner,
+                _ => break,
+            }
+        }
+        let kind = match err {
+            ignore::Error::Loop { child, .. } => {
+                path = path.or(Some(child.clone()));
+                "loop"
+            }
+            ignore::Error::Io(e) => io_kind(e),
+            _ => "other",
+        };
+        WalkError {
+            kind,
+            path,
+            message,
+        }
+    }
+}
+
+fn io_kind(e: &io::Error) -> &'static str {
+    match e.kind() {
+        io::ErrorKind::PermissionDenied => "permission_denied",
+        io::ErrorKind::NotFound => "not_found",
+        _ => "io",
+    }
+}
+
+/// Receives what a walk finds.
+pub trait Sink: Sync {
+    fn push(&self, item: Arc<str>);
+    /// An entry could not be read.
+    fn error(&self, error: WalkError);
+    /// A directory was entered, whether or not it is listed.
+    fn dir(&self) {}
+}
+
+/// Walks `opts.path`, pushing every entry into `sink` until the walk
+/// finishes or `cancel` is set.
+pub fn walk(opts: &WalkOptions, cancel: &AtomicBool, sink: &dyn Sink) {
+    let content = match opts.content.as_deref().map(RegexMatcher::new).transpose() {
+        Ok(m) => m,
+        Err(e) => return eprintln!("gf: {e}"),
+    };
+
+    // directories are walked into whatever the globs, but only listed if
+    // they match
+    let globs = match opts.globs.is_empty() {
+        true => None,
+        false => overrides(opts).ok(),
+    };
+    let now = SystemTime::now();
+    let visitor = || -> Visitor {
+        let content = content.clone();
+        let globs = globs.clone();
+        let mut searcher = SearcherBuilder::new()
+            .line_number(true)
+            .binary_detection(BinaryDetection::quit(b'\x00'))
+            .build();
+        Box::new(move |entry| {
+            if cancel.load(Ordering::Relaxed) {
+                return WalkState::Quit;
+            }
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    sink.error(e.into());
+                    return WalkState::Continue;
+                }
+            };
+            if entry.file_type().is_some_and(|t| t.is_dir()) {
+                sink.dir();
+                if globs
+                    .as_ref()
+                    .is_some_and(|globs| !globs.matched(entry.path(), true).is_whitelist())
+                {
+                    return WalkState::Continue;
+                }
+            }
+            if entry.depth() < opts.min_depth.unwrap_or(0) {
+                return WalkState::Continue;
+            }
+            if !opts.kinds.is_empty() && !opts.kinds.iter().any(|k| k.matches(&entry)) {
+                return WalkState::Continue;
+            }
+            if !opts.metadata_matches(&entry, now) {
+                return WalkState::Continue;
+            }
+            match &content {
+                Some(matcher) => search(&mut searcher, matcher, &entry, sink),
+                None => sink.push(entry.path().to_string_lossy().into()),
+            }
+            WalkState::Continue
+        })
+    };
+    let mut builder = builder(opts, Path::new(&opts.path));
+    // a subtree is mostly a single new file, not worth a pool of threads
+    if opts.subtree_of.is_some() {
+        let mut visit = visitor();
+        for entry in builder.build() {
+            if visit(entry) == WalkState::Quit {
+                break;
+            }
+        }
+    } else {
+        let threads = thread::available_parallelism().unwrap().get();
+        builder.threads(threads).build_parallel().run(visitor);
+    }
+}
+
+type Visitor<'a> = Box<dyn FnMut(Result<DirEntry, ignore::Error>) -> WalkState + Send + 'a>;
+
+/// Lists the entries directly inside `dir` that a walk with `opts` would
+/// visit.
+pub fn list(opts: &WalkOptions, dir: &Path) -> Vec<PathBuf> {
+    builder(opts, dir)
+        .max_depth(Some(1))
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.depth() == 1)
+        .map(DirEntry::into_path)
+        .collect()
+}
+
+fn builder(opts: &WalkOptions, root: &Path) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .require_git(false)
+        .follow_links(opts.follow_symlinks)
+        .standard_filters(!opts.no_ignore)
+        .hidden(!opts.hidden)
+        .max_depth(opts.max_depth)
+        .same_file_system(opts.one_file_system)
+        .max_filesize(opts.max_filesize);
+    // globs are relative to the root even for a subtree
+    if let Ok(overrides) = overrides(opts) {
+        builder.overrides(overrides);
+    }
+    if let Ok(types) = file_types(opts) {
+        builder.types(types);
+    }
+    builder
+}
+
+fn overrides(opts: &WalkOptions) -> Result<Override, ignore::Error> {
+    let mut builder = OverrideBuilder::new(opts.root());
+    for glob in &opts.globs {
+        builder.add(glob)?;
+    }
+    for glob in &opts.excludes {
+        builder.add(&format!("!{glob}"))?;
+    }
+    builder.build()
+}
+
+/// The file type matcher for `opts`, failing on unknown type names.
+pub fn file_types(opts: &WalkOptions) -> Result<Types, ignore::Error> {
+    let mut builder = types_builder(opts);
+    for name in &opts.types {
+        builder.select(name);
+    }
+    for name in &opts.types_not {
+        builder.negate(name);
+    }
+    builder.build()
+}
+
+/// Every known file type, including the ones added with `type_defs`.
+pub fn type_definitions(opts: &WalkOptions) -> Vec<FileTypeDef> {
+    types_builder(opts).definitions()
+}
+
+fn types_builder(opts: &WalkOptions) -> TypesBuilder {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    for def in &opts.type_defs {
+        // checked when added
+        let _ = builder.add_def(def);
+    }
+    builder
+}
+
+/// Checks a `name:glob` type definition.
+pub fn parse_type_def(def: &str) -> Result<String, ignore::Error> {
+    TypesBuilder::new().add_def(def)?;
+    Ok(def.to_string())
+}
+
+/// Checks that `glob` can be used in `globs` or `excludes`.
+pub fn parse_glob(glob: &str) -> Result<String, ignore::Error> {
+    OverrideBuilder::new("").add(glob)?;
+    Ok(glob.to_string())
+}
+
+/// Pushes every matching line of `entry` as `path:line:col:text`.
+fn search(searcher: &mut Searcher, matcher: &RegexMatcher, entry: &DirEntry, sink: &dyn Sink) {
+    if !entry.file_type().is_some_and(|t| t.is_file()) {
+        return;
+    }
+    let path = entry.path();
+    let result = searcher.search_path(
+        matcher,
+        path,
+        Lossy(|line_number, line| {
+            sink.push(format_match(matcher, path, line_number, line).into());
+            Ok(true)
+        }),
+    );
+    if let Err(e) = result {
+        sink.error(WalkError {
+            kind: io_kind(&e),
+            path: Some(path.to_path_buf()),
+            message: e.to_string(),
+        });
+    }
+}
+
+fn format_match(matcher: &RegexMatcher, path: &Path, line_number: u64, line: &str) -> String {
+    let col = match matcher.find(line.as_bytes()) {
+        Ok(Some(m)) => m.start() + 1,
+        _ => 1,
+    };
+    format!(
+        "{}:{line_number}:{col}:{}",
+        path.display(),
+        line.trim_end_matches(['\r', '\n'])
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subtree_depth() {
+        let opts = WalkOptions {
+            path: "root".into(),
+            max_depth: Some(3),
+            min_depth: Some(2),
+            ..Default::default()
+        };
+        let sub = opts.subtree("root/a/b", 2).unwrap();
+        assert_eq!(sub.path, "root/a/b");
+        assert_eq!(sub.subtree_of.as_deref(), Some("root"));
+        assert_eq!((sub.max_depth, sub.min_depth), (Some(1), Some(0)));
+        let sub = opts.subtree("root/a/b/c", 3).unwrap();
+        assert_eq!(sub.max_depth, Some(0));
+        assert!(opts.subtree("root/a/b/c/d", 4).is_none());
+    }
+
+    #[test]
+    fn subtree_of_subtree_keeps_root() {
+        let opts = WalkOptions {
+            path: "root".into(),
+            ..Default::default()
+        };
+        let sub = opts.subtree("root/a", 1).unwrap();
+        let sub = sub.subtree("root/a/b", 1).unwrap();
+        assert_eq!(sub.subtree_of.as_deref(), Some("root"));
+        assert_eq!((sub.max_depth, sub.min_depth), (None, None));
+    }
+}
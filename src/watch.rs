@@ -0,0 +1,170 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use crate::index::Index;
+use crate::walk::{WalkOptions, list, walk};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        Weak,
+        atomic::AtomicBool,
+        mpsc::{self, Receiver, RecvTimeoutError},
+    },
+    time::{Duration, Instant},
+};
+
+/// How long to wait for more events before applying a batch of changes.
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Watches the root of a walk for changes.
+pub struct Watch {
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<notify::Event>>,
+    /// The canonical root, which event paths are relative to.
+    root: PathBuf,
+    /// Directories a walk enters, as found so far.
+    visible: HashSet<PathBuf>,
+}
+
+impl Watch {
+    /// Starts watching `opts.path`. Content searches are not watched since
+    /// their items are lines, not paths.
+    pub fn new(opts: &WalkOptions) -> Option<Self> {
+        if opts.content.is_some() {
+            return None;
+        }
+        let root = fs::canonicalize(&opts.path).ok()?;
+        let (tx, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(tx).and_then(|mut watcher| {
+            watcher.watch(&root, RecursiveMode::Recursive)?;
+            Ok(watcher)
+        });
+        match watcher {
+            Ok(watcher) => Some(Watch {
+                _watcher: watcher,
+                events,
+                root,
+                visible: HashSet::new(),
+            }),
+            Err(e) => {
+                eprintln!("gf: watching {}: {e}", opts.path);
+                None
+            }
+        }
+    }
+
+    /// Applies changes to `index` until it is dropped or canceled.
+    pub fn run(mut self, index: Weak<Index>, opts: &WalkOptions) {
+        loop {
+            let event = match self.events.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => Some(event),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+            let Some(index) = index.upgrade().filter(|index| !index.canceled()) else {
+                return;
+            };
+            let Some(event) = event else { continue };
+
+            let mut paths = BTreeSet::new();
+            paths.extend(event.map(|e| e.paths).unwrap_or_default());
+            let deadline = Instant::now() + DEBOUNCE;
+            while let Ok(event) = self
+                .events
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            {
+                paths.extend(event.map(|e| e.paths).unwrap_or_default());
+            }
+            self.apply(&index, opts, paths);
+        }
+    }
+
+    /// Adds the `paths` a walk would have found and removes the ones that no
+    /// longer exist.
+    fn apply(&mut self, index: &Index, opts: &WalkOptions, paths: BTreeSet<PathBuf>) {
+        let root = Path::new(&opts.path);
+        let mut paths: Vec<PathBuf> = paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(&self.root).ok())
+            .filter(|rel| !rel.as_os_str().is_empty())
+            .map(|rel| root.join(rel))
+            .collect();
+        // parents first, a new directory is walked before its contents show up
+        paths.sort_by_key(|path| path.as_os_str().len());
+
+        // only the top-most removed paths, everything below goes with them
+        let mut removed = HashSet::new();
+        // many new files are often in the same directory, list it once
+        let mut listed = HashMap::new();
+        for path in paths {
+            if path.ancestors().skip(1).any(|dir| removed.contains(dir)) {
+                continue;
+            }
+            if fs::symlink_metadata(&path).is_err() {
+                removed.insert(path);
+                continue;
+            }
+            let item = path.to_string_lossy();
+            if index.contains(&item) {
+                continue;
+            }
+            // only paths the walk would have visited, this is where ignore
+            // files and hidden filters apply
+            if path == root || !self.visible(root, opts, &path, &mut listed) {
+                continue;
+            }
+            let depth = path
+                .strip_prefix(root)
+                .map_or(0, |rel| rel.components().count());
+            let Some(subtree) = opts.subtree(&item, depth) else {
+                continue;
+            };
+            walk(&subtree, &AtomicBool::new(false), index);
+        }
+        if !removed.is_empty() {
+            self.visible
+                .retain(|dir| !dir.ancestors().any(|dir| removed.contains(dir)));
+            let removed: Vec<String> = removed
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect();
+            index.remove(&removed);
+        }
+    }
+
+    /// Whether a walk reaches `path`, checked from the root down. Directories
+    /// are remembered, `listed` keeps what the walk sees in each parent.
+    fn visible(
+        &mut self,
+        root: &Path,
+        opts: &WalkOptions,
+        path: &Path,
+        listed: &mut HashMap<PathBuf, HashSet<PathBuf>>,
+    ) -> bool {
+        if path == root || self.visible.contains(path) {
+            return true;
+        }
+        let Some(parent) = path.parent() else {
+            return false;
+        };
+        if !self.visible(root, opts, parent, listed) {
+            return false;
+        }
+        let entries = listed
+            .entry(parent.to_path_buf())
+            .or_insert_with(|| list(opts, parent).into_iter().collect());
+        if !entries.contains(path) {
+            return false;
+        }
+        if path.is_dir() {
+            self.visible.insert(path.to_path_buf());
+        }
+        true
+    }
+}